@@ -149,6 +149,9 @@ impl Builder for BareBuilder {
         }
         if self.options.base.sign {
             cmd.arg("--sign");
+            if let Some(key) = &self.options.base.sign_key {
+                cmd.arg("--key").arg(key);
+            }
         }
         if self.options.base.skip_checksum {
             cmd.arg("--skipchecksums");
@@ -19,6 +19,7 @@ type IOResult<T> = std::result::Result<T, std::io::Error>;
 pub struct BuildOptions {
     check: bool,
     sign: bool,
+    sign_key: Option<String>,
     skip_checksum: bool,
     skip_pgp_check: bool,
     verbose: bool,
@@ -30,6 +31,7 @@ impl BuildOptions {
     }
     setter_copy!(check, bool);
     setter_copy!(sign, bool);
+    setter_option_clone!(sign_key, String);
     setter_copy!(skip_checksum, bool);
     setter_copy!(skip_pgp_check, bool);
     setter_copy!(verbose, bool);
@@ -4,3 +4,14 @@ pub const PACMAN_CONF_PATH: &str = "/etc/pacman.conf";
 pub const MAKEPKG_CONF_PATH: &str = "/etc/makepkg.conf";
 pub const STORAGE_MEMORY_LIMIT: u64 = 104_857_600; // 100 MB
 pub const LOCK_FILE_VERSION: u32 = 1;
+pub const JOURNAL_INLINE_LIMIT: u64 = 1_048_576; // 1 MB
+pub const BYTESTREAM_CHUNK_SIZE: usize = 65_536; // 64 KB
+pub const LEASE_TTL: i64 = 60; // seconds
+pub const GC_GRACE_PERIOD: i64 = 86_400; // seconds
+pub const DOWNLOAD_RETRIES: usize = 3;
+pub const CACHE_SAVE_INTERVAL: usize = 32; // cache hits between saves of cache index
+pub const S3_PART_SIZE: u64 = 8_388_608; // 8 MB
+pub const S3_MIN_PART_SIZE: u64 = 5_242_880; // 5 MB, required by s3 except for the last part
+pub const S3_MAX_PARTS: u64 = 10_000;
+pub const S3_UPLOAD_CONCURRENCY: usize = 4;
+pub const S3_UPLOAD_EXPIRY: i64 = 86_400; // seconds, older unfinished uploads are aborted
@@ -1,18 +1,69 @@
-use std::io::Cursor;
+use std::io::{Cursor, Read};
+use std::ops::RangeInclusive;
 use std::path::Path;
 
 use tar::{Builder, Header};
 
-use crate::error::Result;
+use crate::error::{Error, Result};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::Zstd
+    }
+}
+
+impl Compression {
+    pub const ALL: [Self; 4] = [Self::Zstd, Self::Xz, Self::Gzip, Self::None];
+
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "tar",
+            Compression::Gzip => "tar.gz",
+            Compression::Xz => "tar.xz",
+            Compression::Zstd => "tar.zst",
+        }
+    }
+
+    // accepted compression levels, None if levels are ignored
+    pub const fn levels(self) -> Option<RangeInclusive<u32>> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip | Compression::Xz => Some(0..=9),
+            // 0 is the default level of zstd
+            Compression::Zstd => Some(0..=22),
+        }
+    }
+
+    pub fn check_level(self, level: u32) -> Result<()> {
+        match self.levels() {
+            Some(levels) if !levels.contains(&level) => Err(Error::InvalidCompressionLevel(level)),
+            _ => Ok(()),
+        }
+    }
+}
 
 pub struct ArchiveBuilder {
     builder: Builder<Vec<u8>>,
+    compression: Compression,
+    level: Option<u32>, // use default level of the algorithm if None
 }
 
 impl Default for ArchiveBuilder {
     fn default() -> Self {
         let builder = Builder::new(vec![]);
-        Self { builder }
+        Self {
+            builder,
+            compression: Default::default(),
+            level: None,
+        }
     }
 }
 
@@ -20,6 +71,8 @@ impl ArchiveBuilder {
     pub fn new() -> Self {
         Default::default()
     }
+    setter_copy!(compression, Compression);
+    setter_copy!(level, Option<u32>);
 
     pub fn append_data(&mut self, path: impl AsRef<Path>, data: &[u8]) -> Result<()> {
         let mut header = Header::new_gnu();
@@ -30,9 +83,32 @@ impl ArchiveBuilder {
         Ok(())
     }
 
+    // fails if the level is out of the range of the compression
     pub fn build(mut self) -> Result<Vec<u8>> {
+        if let Some(level) = self.level {
+            self.compression.check_level(level)?;
+        }
         self.builder.finish()?;
         let tar = self.builder.into_inner()?;
-        Ok(zstd::encode_all(Cursor::new(tar), 0)?)
+        Ok(match self.compression {
+            Compression::None => tar,
+            Compression::Gzip => {
+                let level = self
+                    .level
+                    .map_or_else(flate2::Compression::default, flate2::Compression::new);
+                let mut data = vec![];
+                flate2::read::GzEncoder::new(Cursor::new(tar), level).read_to_end(&mut data)?;
+                data
+            }
+            Compression::Xz => {
+                let mut data = vec![];
+                xz2::read::XzEncoder::new(Cursor::new(tar), self.level.unwrap_or(6))
+                    .read_to_end(&mut data)?;
+                data
+            }
+            Compression::Zstd => {
+                zstd::encode_all(Cursor::new(tar), self.level.map_or(0, |level| level as i32))?
+            }
+        })
     }
 }
@@ -1,46 +1,79 @@
 use std::fs::File;
-use std::io::{Cursor, Read};
+use std::io::{self, Cursor, ErrorKind, Read};
 use std::path::Path;
 
+use infer::Infer;
+use lazy_static::lazy_static;
 use tar::Archive as TarArchive;
+use xz2::stream::Stream;
 
 use crate::error::{Error, Result};
 
+lazy_static! {
+    static ref INFER: Infer = {
+        let mut infer = Infer::new();
+        infer.add("application/x-lz4", "lz4", |buf| {
+            buf.starts_with(b"\x04\x22\x4d\x18")
+        });
+        infer.add("application/x-lzip", "lz", |buf| buf.starts_with(b"LZIP"));
+        infer
+    };
+}
+
+// lzip is a lzma stream with its own header, so convert it to a .lzma header
+// NOTE only the first member of multi-member lzip files is decoded
+fn lzip_to_lzma<'a>(mut reader: impl Read + 'a) -> Result<impl Read + 'a> {
+    let mut header = [0; 6];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != b"LZIP" || header[4] != 1 {
+        return Err(Error::ArchiveError);
+    }
+    // bits 4-0: log2 of base size, bits 7-5: fraction of base size / 16 to subtract
+    let base = 1u32 << (header[5] & 0x1f);
+    let dict_size = base - (base / 16) * u32::from(header[5] >> 5);
+
+    let mut lzma_header = vec![0x5d]; // lc=3, lp=0, pb=2
+    lzma_header.extend_from_slice(&dict_size.to_le_bytes());
+    lzma_header.extend_from_slice(&u64::MAX.to_le_bytes()); // unknown size, end marker present
+    Ok(Cursor::new(lzma_header).chain(reader))
+}
+
 pub struct ArchiveReader {
     data: Vec<u8>,
 }
 
 impl ArchiveReader {
-    pub fn from_reader(mut reader: impl Read) -> Result<Self> {
-        let mut head = [0; 512];
-        let head_bytes = reader.read(&mut head)?;
-        let mime = infer::get(&head).ok_or(Error::ArchiveError)?;
-
-        let mut reader = if head_bytes == 512 {
-            Box::new(Cursor::new(head).chain(reader)) as Box<dyn Read>
-        } else {
-            Box::new(&head[..head_bytes]) as Box<dyn Read>
-        };
+    pub fn from_reader(reader: impl Read) -> Result<Self> {
         let mut data: Vec<u8> = Vec::new();
-        match mime.mime_type() {
-            "application/zstd" => {
-                zstd::stream::copy_decode(reader, &mut data)?;
-            }
-            "application/gzip" => {
-                let mut decoder = flate2::read::GzDecoder::new(reader);
-                decoder.read_to_end(&mut data)?;
-            }
-            "application/x-xz" => {
-                let mut decoder = xz2::read::XzDecoder::new(reader);
-                decoder.read_to_end(&mut data)?;
-            }
-            "application/x-tar" => {
-                reader.read_to_end(&mut data)?;
-            }
-            _ => return Err(Error::ArchiveError),
-        }
+        Self::stream(reader)?.read_to_end(&mut data)?;
         Ok(Self { data })
     }
+
+    // decompress on the fly without buffering the whole archive
+    pub fn stream<'a>(mut reader: impl Read + 'a) -> Result<Box<dyn Read + 'a>> {
+        let mut head = Vec::with_capacity(512);
+        (&mut reader).take(512).read_to_end(&mut head)?;
+        let mime = INFER.get(&head).ok_or(Error::ArchiveError)?;
+
+        let reader = Cursor::new(head).chain(reader);
+        Ok(match mime.mime_type() {
+            "application/zstd" => Box::new(zstd::stream::read::Decoder::new(reader)?),
+            "application/gzip" => Box::new(flate2::read::GzDecoder::new(reader)),
+            "application/x-xz" => Box::new(xz2::read::XzDecoder::new(reader)),
+            "application/x-bzip2" => Box::new(bzip2::read::BzDecoder::new(reader)),
+            "application/x-lz4" => Box::new(lz4::Decoder::new(reader)?),
+            "application/x-lzip" => {
+                let stream = Stream::new_lzma_decoder(u64::MAX)
+                    .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+                Box::new(xz2::read::XzDecoder::new_stream(
+                    lzip_to_lzma(reader)?,
+                    stream,
+                ))
+            }
+            "application/x-tar" => Box::new(reader),
+            mime => return Err(Error::UnsupportedArchive(mime.to_string())),
+        })
+    }
     pub fn from_u8(file: &[u8]) -> Result<Self> {
         Self::from_reader(file)
     }
@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, ErrorKind, Read};
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::types::*;
+
+use super::decompressor::ArchiveReader;
+use super::pacman::{find_db, BuildTarget, DBBuilder};
+use super::reader::read_archive;
+use super::types::*;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct RawEntry {
+    version: Version,
+    desc: Vec<u8>,
+    files: Option<Vec<u8>>, // None if files database isn't available
+}
+
+// Edit an existing database in place like repo-add & repo-remove.
+// Entries are kept serialized, so untouched packages are written back as is.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct DBEditor {
+    entries: BTreeMap<String, RawEntry>,
+}
+
+// read the value of a field from desc, e.g. `%NAME%\nfoo\n`
+fn desc_field<'a>(desc: &'a str, field: &str) -> Option<&'a str> {
+    let mut lines = desc.lines();
+    lines.find(|line| line.trim() == field)?;
+    lines.next().map(str::trim)
+}
+
+impl DBEditor {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // load a database from its .db archive and (optionally) .files archive
+    pub fn from_archives(db: impl Read, files: Option<impl Read>) -> Result<Self> {
+        let db_entries = read_archive(ArchiveReader::from_reader(db)?)?;
+        let files_entries = files
+            .map(|files| ArchiveReader::from_reader(files).and_then(read_archive))
+            .transpose()?;
+
+        let mut entries = BTreeMap::new();
+        for (key, (dir_name, desc)) in db_entries {
+            if !key.ends_with("/desc") {
+                continue;
+            }
+            let (name, version) = {
+                let desc_str = String::from_utf8_lossy(&desc);
+                let name = desc_field(&desc_str, "%NAME%").ok_or(Error::PackageError)?;
+                let version = desc_field(&desc_str, "%VERSION%").ok_or(Error::PackageError)?;
+                (name.to_string(), version.to_string())
+            };
+            let files = files_entries.as_ref().and_then(|files_entries| {
+                files_entries
+                    .get(&format!("{}/files", dir_name))
+                    .map(|(_, files)| files.clone())
+            });
+            entries.insert(
+                name,
+                RawEntry {
+                    version: Version(version),
+                    desc,
+                    files,
+                },
+            );
+        }
+        Ok(Self { entries })
+    }
+
+    // open `{repo}.db` and `{repo}.files` (if exists) in given directory
+    pub fn open(path: impl AsRef<Path>, repo: &str) -> Result<Self> {
+        let db_path = find_db(path.as_ref(), repo, "db")
+            .ok_or_else(|| io::Error::from(ErrorKind::NotFound))?;
+        let files = find_db(path.as_ref(), repo, "files")
+            .map(fs::File::open)
+            .transpose()?;
+        Self::from_archives(fs::File::open(db_path)?, files)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn version(&self, name: &str) -> Option<&Version> {
+        self.entries.get(name).map(|entry| &entry.version)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    // add an entry, replacing any older version of the same package
+    // returns false if a newer version is already present
+    pub fn add_entry(&mut self, desc: &PacmanEntry, files: &[String]) -> Result<bool> {
+        if self
+            .version(&desc.name)
+            .map_or(false, |version| *version > desc.version)
+        {
+            return Ok(false);
+        }
+        let desc_content = archlinux_repo_parser::to_string(desc).unwrap(); // TODO error handling
+        let files_content = format!("%FILES%\n{}", files.join("\n"));
+        self.entries.insert(
+            desc.name.clone(),
+            RawEntry {
+                version: desc.version.clone(),
+                desc: desc_content.into_bytes(),
+                files: Some(files_content.into_bytes()),
+            },
+        );
+        Ok(true)
+    }
+
+    // index a package file and add it
+    pub fn add_package(&mut self, pkg: &Path) -> Result<bool> {
+        let (desc, files) = DBBuilder::index_package(pkg)?;
+        self.add_entry(&desc, &files)
+    }
+
+    // returns false if the package isn't present
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.entries.remove(name).is_some()
+    }
+
+    pub fn build(&self, mut target: BuildTarget) -> Result<()> {
+        for (name, entry) in &self.entries {
+            target.append_raw(
+                &format!("{}-{}", name, entry.version),
+                &entry.desc,
+                entry.files.as_deref(),
+            )?;
+        }
+        target.build()
+    }
+}
@@ -1,6 +1,15 @@
+pub use compressor::{ArchiveBuilder, Compression};
+pub use decompressor::ArchiveReader;
+pub use editor::DBEditor;
+pub use pacman::{ArchiveOptions, BuildTarget, Checksums, DBBuilder, DBLink};
+pub use reader::{parse_files, DBEntry, PacmanDB};
+pub use types::*;
+
 mod compressor;
 mod decompressor;
+mod editor;
 mod pacman;
+mod reader;
 mod types;
 
 #[cfg(test)]
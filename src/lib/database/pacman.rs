@@ -1,24 +1,72 @@
 use std::fs;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 
+use goblin::elf::Elf;
+use memmap2::Mmap;
 use pkginfo::errors::Error as PkgInfoError;
 use pkginfo::PkgInfo;
+use rayon::prelude::*;
 use sha2::{Digest, Sha256};
 use tar::Archive as TarArchive;
 
-use crate::error::{Error, Result};
+use crate::error::{CommandError, Error, GpgError, Result};
+use crate::utils::map_gpg_code;
 
-use super::compressor::ArchiveBuilder;
+use super::compressor::{ArchiveBuilder, Compression};
 use super::decompressor::ArchiveReader;
 use super::types::*;
 
+// How `{repo}.db` & `{repo}.files` point to the actual archives.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum DBLink {
+    Symlink,
+    Copy, // for storage backends without symlink support
+    None,
+}
+
+impl Default for DBLink {
+    fn default() -> Self {
+        Self::Symlink
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub struct ArchiveOptions {
+    compression: Compression,
+    level: Option<u32>,
+    link: DBLink,
+    skip_files: bool,
+    sign_key: Option<String>, // sign .db & .files archives with this gpg key
+}
+
+impl ArchiveOptions {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    setter_copy!(compression, Compression);
+    setter_copy!(level, Option<u32>);
+    setter_copy!(link, DBLink);
+    setter_copy!(skip_files, bool);
+    setter_option_clone!(sign_key, String);
+
+    // reject levels the compression doesn't accept
+    pub fn check(&self) -> Result<()> {
+        match self.level {
+            Some(level) => self.compression.check_level(level),
+            None => Ok(()),
+        }
+    }
+}
+
 pub enum BuildTarget {
     Folder(PathBuf),
     Archive {
         path: PathBuf,
         repo: String,
+        options: ArchiveOptions,
         desc_builder: ArchiveBuilder,
         files_builder: ArchiveBuilder,
     },
@@ -31,33 +79,56 @@ impl BuildTarget {
             |repo| Self::Archive {
                 path: path.as_ref().to_path_buf(),
                 repo: repo.to_string(),
+                options: Default::default(),
                 desc_builder: Default::default(),
                 files_builder: Default::default(),
             },
         )
     }
 
+    // set archive options (ignored by folder target)
+    pub fn with_options(mut self, archive_options: ArchiveOptions) -> Self {
+        if let Self::Archive { options, .. } = &mut self {
+            *options = archive_options;
+        }
+        self
+    }
+
     // append package to target
     pub fn append_pkg(&mut self, desc: &PacmanEntry, files: &[String]) -> Result<()> {
-        let dir_name = PathBuf::from(format!("{}-{}", desc.name, desc.version).as_str());
+        let dir_name = format!("{}-{}", desc.name, desc.version);
         let desc_content = archlinux_repo_parser::to_string(desc).unwrap(); // TODO error handling
         let files_content = format!("%FILES%\n{}", files.join("\n"));
+        self.append_raw(
+            &dir_name,
+            desc_content.as_ref(),
+            Some(files_content.as_ref()),
+        )
+    }
+
+    // append serialized desc & files entries to target
+    // files entry is omitted if it's unknown
+    pub fn append_raw(&mut self, dir_name: &str, desc: &[u8], files: Option<&[u8]>) -> Result<()> {
+        let dir_name = PathBuf::from(dir_name);
         match self {
             BuildTarget::Folder(target) => {
                 let pkg_dir = target.join(dir_name);
                 fs::create_dir(&pkg_dir)?;
-                fs::write(pkg_dir.join("desc"), desc_content)?;
-                fs::write(pkg_dir.join("files"), files_content)?;
+                fs::write(pkg_dir.join("desc"), desc)?;
+                if let Some(files) = files {
+                    fs::write(pkg_dir.join("files"), files)?;
+                }
             }
             BuildTarget::Archive {
-                path: _,
-                repo: _,
                 desc_builder,
                 files_builder,
+                ..
             } => {
-                desc_builder.append_data(dir_name.join("desc"), desc_content.as_ref())?;
-                files_builder.append_data(dir_name.join("desc"), desc_content.as_ref())?;
-                files_builder.append_data(dir_name.join("files"), files_content.as_ref())?;
+                desc_builder.append_data(dir_name.join("desc"), desc)?;
+                files_builder.append_data(dir_name.join("desc"), desc)?;
+                if let Some(files) = files {
+                    files_builder.append_data(dir_name.join("files"), files)?;
+                }
             }
         }
         Ok(())
@@ -68,26 +139,159 @@ impl BuildTarget {
         if let BuildTarget::Archive {
             path,
             repo,
+            options,
             desc_builder,
             files_builder,
         } = self
         {
-            let desc_path = path.join(format!("{}.db.tar.zst", repo));
-            let desc_data = desc_builder.build()?;
-            let mut desc_file = File::create(desc_path)?;
-            desc_file.write_all(&*desc_data)?;
-            desc_file.sync_all()?;
-
-            let files_path = path.join(format!("{}.files.tar.zst", repo));
-            let files_data = files_builder.build()?;
-            let mut files_file = File::create(files_path)?;
-            files_file.write_all(&*files_data)?;
-            files_file.sync_all()?;
+            write_db(&path, &repo, "db", desc_builder, &options)?;
+            if !options.skip_files {
+                write_db(&path, &repo, "files", files_builder, &options)?;
+            }
         }
         Ok(())
     }
 }
 
+// locate `{repo}.{kind}` or its archive in given directory
+pub(super) fn find_db(path: &Path, repo: &str, kind: &str) -> Option<PathBuf> {
+    let link = path.join(format!("{}.{}", repo, kind));
+    if link.exists() {
+        return Some(link);
+    }
+    Compression::ALL
+        .iter()
+        .map(|compression| path.join(format!("{}.{}.{}", repo, kind, compression.extension())))
+        .find(|archive| archive.exists())
+}
+
+// write `{repo}.{kind}.tar.*` and its signature & links
+fn write_db(
+    path: &Path,
+    repo: &str,
+    kind: &str,
+    builder: ArchiveBuilder,
+    options: &ArchiveOptions,
+) -> Result<()> {
+    let archive_name = format!("{}.{}.{}", repo, kind, options.compression.extension());
+    let archive_path = path.join(&archive_name);
+    let data = builder
+        .compression(options.compression)
+        .level(options.level)
+        .build()?;
+    let mut file = File::create(&archive_path)?;
+    file.write_all(&*data)?;
+    file.sync_all()?;
+
+    let mut files = vec![(archive_name.clone(), format!("{}.{}", repo, kind))];
+    if let Some(key) = &options.sign_key {
+        sign_file(&archive_path, key)?;
+        files.push((
+            format!("{}.sig", archive_name),
+            format!("{}.{}.sig", repo, kind),
+        ));
+    }
+
+    for (src, dest) in files {
+        let dest = path.join(dest);
+        match options.link {
+            DBLink::Symlink => {
+                remove_if_exists(&dest)?;
+                std::os::unix::fs::symlink(src, dest)?;
+            }
+            DBLink::Copy => {
+                remove_if_exists(&dest)?;
+                fs::copy(path.join(src), dest)?;
+            }
+            DBLink::None => (),
+        }
+    }
+    Ok(())
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// create a detached binary signature `{path}.sig`
+fn sign_file(path: &Path, key: &str) -> Result<()> {
+    let mut sig_path = path.as_os_str().to_os_string();
+    sig_path.push(".sig");
+    let status = Command::new("gpg")
+        .args(&["--batch", "--yes", "--no-armor", "--detach-sign"])
+        .arg("--local-user")
+        .arg(key)
+        .arg("--output")
+        .arg(sig_path)
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    status
+        .code()
+        .map_or(Some(GpgError::Signal), map_gpg_code)
+        .map_or(Ok(()), |e| Err(CommandError::Gpg(e).into()))
+}
+
+// read the detached signature `{pkg}.sig` if there's one
+fn read_signature(pkg: &Path) -> Result<Option<String>> {
+    let mut sig_path = pkg.as_os_str().to_os_string();
+    sig_path.push(".sig");
+    match fs::read(sig_path) {
+        Ok(sig) => Ok(Some(base64::encode(sig))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Checksums {
+    pub size: u64,
+    pub md5: String,    // in hex
+    pub sha256: String, // in hex
+}
+
+// Compute size & checksums of all data read through it.
+struct HashingReader<R> {
+    inner: R,
+    size: u64,
+    md5: md5::Context,
+    sha256: Sha256,
+}
+
+impl<R> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            size: 0,
+            md5: md5::Context::new(),
+            sha256: Sha256::new(),
+        }
+    }
+
+    fn finalize(self) -> Checksums {
+        Checksums {
+            size: self.size,
+            md5: format!("{:?}", self.md5.compute()),
+            sha256: format!("{:x}", self.sha256.finalize()),
+        }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.size += n as u64;
+        self.md5.consume(&buf[..n]);
+        self.sha256.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct DBBuilder {
     pkgs: Vec<PathBuf>,
@@ -109,8 +313,18 @@ impl DBBuilder {
     }
 
     pub fn build(&self, mut target: BuildTarget) -> Result<()> {
-        for pkg in &self.pkgs {
-            Self::build_single(pkg, &mut target)?;
+        // fail before indexing anything
+        if let BuildTarget::Archive { options, .. } = &target {
+            options.check()?;
+        }
+        // index packages in parallel, but keep the output order stable
+        let indexed = self
+            .pkgs
+            .par_iter()
+            .map(|pkg| Self::index_package(pkg))
+            .collect::<Result<Vec<_>>>()?;
+        for (desc, files) in indexed {
+            target.append_pkg(&desc, &files)?;
         }
         target.build()?;
 
@@ -133,44 +347,103 @@ impl DBBuilder {
                 let name = path.file_name().unwrap().to_string_lossy();
                 if name == ".PKGINFO" {
                     // parse .PKGINFO
-                    info = Some(PkgInfo::parse_file(entry).map_err(|err| match err {
-                        PkgInfoError::IoError(e) => Error::IOError(e),
-                        PkgInfoError::InvalidPackageFormat => Error::PackageError,
-                    })?);
+                    info = Some(Self::parse_pkginfo(entry)?);
                 }
             }
         }
         Ok((files, info))
     }
 
-    // build a single package
-    fn build_single(pkg: &Path, target: &mut BuildTarget) -> Result<()> {
-        // unarchive package
-        let raw = fs::read(pkg)?;
-        let archive = ArchiveReader::from_u8(&raw)?;
-        let tar = archive.to_tar();
+    // parse .PKGINFO & sonames linked against or provided by elf files
+    pub fn collect_links(
+        mut tar: TarArchive<impl Read>,
+    ) -> Result<(PackageLinks, Option<PkgInfo>)> {
+        let mut links = PackageLinks::default();
+        let mut info = None;
+        let mut spill: Option<File> = None;
+        // iterate archive
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = entry.path()?;
+            if path.file_name().unwrap().to_string_lossy() == ".PKGINFO" {
+                // parse .PKGINFO
+                info = Some(Self::parse_pkginfo(entry)?);
+                continue;
+            }
+
+            // check elf magic before reading the whole file
+            let mut magic = vec![];
+            (&mut entry).take(4).read_to_end(&mut magic)?;
+            if magic != b"\x7fELF" {
+                continue;
+            }
+            // spill to disk and map it, so large binaries aren't read into memory
+            let spill = match &mut spill {
+                Some(spill) => spill,
+                None => spill.insert(tempfile::tempfile()?),
+            };
+            spill.set_len(0)?;
+            spill.seek(SeekFrom::Start(0))?;
+            io::copy(&mut magic.as_slice().chain(&mut entry), spill)?;
+            // SAFETY: the unnamed temp file isn't reachable by other processes
+            let data = unsafe { Mmap::map(&*spill)? };
+            if let Ok(elf) = Elf::parse(&data) {
+                links.needed.extend(
+                    elf.libraries
+                        .iter()
+                        .filter_map(|lib| Soname::parse(lib, elf.is_64)),
+                );
+                links.provided.extend(
+                    elf.soname
+                        .and_then(|soname| Soname::parse(soname, elf.is_64)),
+                );
+            }
+        }
+        // libraries shipped in the same package aren't external links
+        links.needed = &links.needed - &links.provided;
+        Ok((links, info))
+    }
+
+    fn parse_pkginfo(entry: impl Read) -> Result<PkgInfo> {
+        PkgInfo::parse_file(entry).map_err(|err| match err {
+            PkgInfoError::IoError(e) => Error::IOError(e),
+            PkgInfoError::InvalidPackageFormat => Error::PackageError,
+        })
+    }
+
+    // compute CSIZE, MD5SUM & SHA256SUM of a package
+    pub fn checksum(reader: impl Read) -> Result<Checksums> {
+        let mut reader = HashingReader::new(reader);
+        io::copy(&mut reader, &mut io::sink())?;
+        Ok(reader.finalize())
+    }
 
-        // parse package files and .PKGINFO
-        let (files, info) = Self::collect_info(tar)?;
+    // generate desc entry & file list of a package
+    pub fn index_package(pkg: &Path) -> Result<(PacmanEntry, Vec<String>)> {
+        // unarchive package, hashing it on the fly
+        let mut reader = HashingReader::new(File::open(pkg)?);
+        let (files, info) =
+            Self::collect_info(TarArchive::new(ArchiveReader::stream(&mut reader)?))?;
         let info = info.ok_or(Error::PackageError)?;
+        // trailing data isn't consumed by tar
+        io::copy(&mut reader, &mut io::sink())?;
+        let checksums = reader.finalize();
 
         // convert .PKGINFO to desc format
         let desc_builder: PacmanEntryBuilder = info.into();
-        // TODO PGP
         // add remaining fields
         let desc: PacmanEntry = desc_builder
+            .pgp_signature(read_signature(pkg)?)
             .file_name(pkg.file_name().unwrap().to_string_lossy().to_string())
-            .compressed_size(fs::metadata(pkg)?.len())
-            .md5_sum(format!("{:?}", md5::compute(&raw)))
-            .sha256_sum({
-                let mut hasher = Sha256::new();
-                hasher.update(&raw);
-                format!("{:x}", hasher.finalize())
-            })
+            .compressed_size(checksums.size)
+            .md5_sum(checksums.md5)
+            .sha256_sum(checksums.sha256)
             .build()
             .unwrap();
 
-        // output files
-        target.append_pkg(&desc, &files)
+        Ok((desc, files))
     }
 }
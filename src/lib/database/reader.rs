@@ -0,0 +1,143 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, ErrorKind, Read};
+use std::ops::Index;
+use std::path::{Component, Path};
+use std::str::FromStr;
+
+use crate::error::{Error, ParseError, Result};
+
+use super::decompressor::ArchiveReader;
+use super::pacman::find_db;
+use super::types::*;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DBEntry {
+    pub desc: PacmanEntry,
+    pub files: Option<Vec<String>>, // None if it's read from a .db archive
+}
+
+// Parsed content of a repo database, indexed by package name.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct PacmanDB {
+    entries: Vec<DBEntry>,
+    index: HashMap<String, usize>,
+}
+
+// map `{pkgname}-{pkgver}/{desc,files}` entries of an archive to their contents
+pub(super) fn read_archive(archive: ArchiveReader) -> Result<BTreeMap<String, (String, Vec<u8>)>> {
+    let mut entries = BTreeMap::new();
+    let mut tar = archive.into_tar();
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.to_path_buf();
+        let mut components = path.components().filter_map(|c| match c {
+            Component::Normal(c) => Some(c.to_string_lossy().to_string()),
+            _ => None,
+        });
+        if let (Some(dir_name), Some(kind)) = (components.next(), components.next()) {
+            let mut data = vec![];
+            entry.read_to_end(&mut data)?;
+            entries.insert(format!("{}/{}", dir_name, kind), (dir_name, data));
+        }
+    }
+    Ok(entries)
+}
+
+impl FromStr for PacmanEntry {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        archlinux_repo_parser::from_str(s).map_err(|e| ParseError::DescError(e.to_string()).into())
+    }
+}
+
+// parse a `files` entry, e.g. `%FILES%\nusr/\nusr/bin/\n`
+pub fn parse_files(files: &str) -> Vec<String> {
+    files
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && *line != "%FILES%")
+        .map(ToString::to_string)
+        .collect()
+}
+
+impl PacmanDB {
+    pub fn new(entries: Vec<DBEntry>) -> Self {
+        let index = entries
+            .iter()
+            .enumerate()
+            .map(|(idx, entry)| (entry.desc.name.clone(), idx))
+            .collect();
+        Self { entries, index }
+    }
+
+    // read a .db or .files archive
+    pub fn from_archive(archive: ArchiveReader) -> Result<Self> {
+        let raw_entries = read_archive(archive)?;
+        let mut entries = vec![];
+        for (key, (dir_name, desc)) in &raw_entries {
+            if !key.ends_with("/desc") {
+                continue;
+            }
+            let desc = PacmanEntry::from_str(&String::from_utf8_lossy(desc))?;
+            let files = raw_entries
+                .get(&format!("{}/files", dir_name))
+                .map(|(_, files)| parse_files(&String::from_utf8_lossy(files)));
+            entries.push(DBEntry { desc, files });
+        }
+        Ok(Self::new(entries))
+    }
+
+    pub fn from_reader(reader: impl Read) -> Result<Self> {
+        Self::from_archive(ArchiveReader::from_reader(reader)?)
+    }
+
+    // open `{repo}.files` if file lists are requested, otherwise `{repo}.db`
+    pub fn open(path: impl AsRef<Path>, repo: &str, with_files: bool) -> Result<Self> {
+        let kind = if with_files { "files" } else { "db" };
+        let db_path = find_db(path.as_ref(), repo, kind)
+            .ok_or_else(|| io::Error::from(ErrorKind::NotFound))?;
+        Self::from_reader(fs::File::open(db_path)?)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&DBEntry> {
+        self.index.get(name).map(|idx| &self.entries[*idx])
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.index.contains_key(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DBEntry> {
+        self.entries.iter()
+    }
+}
+
+impl Index<&str> for PacmanDB {
+    type Output = DBEntry;
+
+    fn index(&self, name: &str) -> &Self::Output {
+        self.get(name).expect("package not found")
+    }
+}
+
+impl IntoIterator for PacmanDB {
+    type Item = DBEntry;
+    type IntoIter = std::vec::IntoIter<DBEntry>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
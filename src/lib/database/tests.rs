@@ -3,8 +3,14 @@ use std::io::Read;
 use std::path::PathBuf;
 
 use rstest::rstest;
+use tempfile::tempdir;
 
-use crate::database::pacman::{BuildTarget, DBBuilder};
+use crate::database::compressor::Compression;
+use crate::database::editor::DBEditor;
+use crate::database::pacman::{ArchiveOptions, BuildTarget, DBBuilder, DBLink};
+use crate::database::reader::PacmanDB;
+use crate::error::Error;
+use crate::types::Version;
 
 use super::decompressor::ArchiveReader;
 
@@ -13,6 +19,9 @@ use super::decompressor::ArchiveReader;
 #[case("test.tar.gz")]
 #[case("test.tar.xz")]
 #[case("test.tar.zst")]
+#[case("test.tar.bz2")]
+#[case("test.tar.lz4")]
+#[case("test.tar.lz")]
 fn must_decompress(#[case] name: &str) {
     println!("decompressing {}", name);
     let path = PathBuf::from("tests/archives/").join(name);
@@ -42,6 +51,15 @@ fn must_decompress(#[case] name: &str) {
     assert_eq!(buffer, "test\n", "file content mismatch");
 }
 
+#[test]
+fn must_reject_unsupported_archive() {
+    let zip = b"PK\x03\x04\x14\x00\x00\x00\x00\x00";
+    assert!(
+        matches!(ArchiveReader::from_u8(zip), Err(Error::UnsupportedArchive(mime)) if mime == "application/zip"),
+        "zip archive accepted"
+    );
+}
+
 #[test]
 pub fn must_build_dir() {
     let mut builder = DBBuilder::new();
@@ -58,3 +76,174 @@ pub fn must_build_dir() {
         .build(BuildTarget::new("tests/output", Some("test")))
         .expect("unable to build db archive");
 }
+
+#[test]
+fn must_edit_db() {
+    let mut pkgs: Vec<_> = fs::read_dir("tests/pkgs")
+        .expect("missing test directory")
+        .map(|path| path.expect("invalid dir entry").path())
+        .collect();
+    pkgs.sort();
+    let last = pkgs.pop().unwrap();
+
+    let dir = tempdir().expect("unable to create temp dir");
+    let mut builder = DBBuilder::new();
+    for pkg in &pkgs {
+        builder.add_file_mut(pkg.clone());
+    }
+    builder
+        .build(BuildTarget::new(dir.path(), Some("test")))
+        .expect("unable to build db archive");
+
+    let mut editor = DBEditor::open(dir.path(), "test").expect("unable to open db");
+    assert_eq!(editor.len(), pkgs.len(), "package count mismatch");
+
+    assert!(editor.add_package(&last).expect("unable to add package"));
+    assert!(editor.remove("aalib"), "package not removed");
+    assert!(!editor.remove("aalib"), "package removed twice");
+
+    // older version mustn't replace the existing one
+    let (mut desc, files) = DBBuilder::index_package(&last).expect("unable to index package");
+    desc.version = Version(String::from("0.0.1-1"));
+    assert!(!editor
+        .add_entry(&desc, &files)
+        .expect("unable to add entry"));
+
+    editor
+        .build(BuildTarget::new(dir.path(), Some("test")))
+        .expect("unable to rebuild db archive");
+
+    let editor = DBEditor::open(dir.path(), "test").expect("unable to reopen db");
+    assert_eq!(
+        editor.names().collect::<Vec<_>>(),
+        vec!["a52dec", "accounts-qml-module", "accountsservice", "acl"],
+        "package list mismatch"
+    );
+    assert_eq!(
+        editor.version("acl"),
+        Some(&Version(String::from("2.3.1-1"))),
+        "version mismatch"
+    );
+}
+
+#[rstest]
+#[case(false)]
+#[case(true)]
+fn must_read_db(#[case] with_files: bool) {
+    let dir = tempdir().expect("unable to create temp dir");
+    let mut builder = DBBuilder::new();
+    let mut expected = vec![];
+    for path in fs::read_dir("tests/pkgs").expect("missing test directory") {
+        let path = path.expect("invalid dir entry").path();
+        expected.push(DBBuilder::index_package(&path).expect("unable to index package"));
+        builder.add_file_mut(path);
+    }
+    builder
+        .build(BuildTarget::new(dir.path(), Some("test")))
+        .expect("unable to build db archive");
+
+    let db = PacmanDB::open(dir.path(), "test", with_files).expect("unable to read db");
+    assert_eq!(db.len(), expected.len(), "package count mismatch");
+    for (desc, files) in expected {
+        let entry = db.get(&desc.name).expect("missing package");
+        assert_eq!(entry.desc, desc, "desc mismatch");
+        assert_eq!(entry.files, with_files.then(|| files), "file list mismatch");
+    }
+    assert!(db.get("missing").is_none());
+}
+
+#[test]
+fn must_embed_signature() {
+    let dir = tempdir().expect("unable to create temp dir");
+    let pkg = dir.path().join("acl-2.3.1-1-x86_64.pkg.tar.zst");
+    fs::copy("tests/pkgs/acl-2.3.1-1-x86_64.pkg.tar.zst", &pkg).expect("unable to copy package");
+
+    let (desc, _) = DBBuilder::index_package(&pkg).expect("unable to index package");
+    assert_eq!(desc.pgp_signature, None, "unexpected signature");
+
+    fs::write(
+        dir.path().join("acl-2.3.1-1-x86_64.pkg.tar.zst.sig"),
+        b"signature",
+    )
+    .expect("unable to write signature");
+    DBBuilder::new()
+        .add_file(pkg)
+        .build(BuildTarget::new(dir.path(), Some("test")))
+        .expect("unable to build db archive");
+
+    let db = PacmanDB::open(dir.path(), "test", false).expect("unable to read db");
+    assert_eq!(
+        db["acl"].desc.pgp_signature.as_deref(),
+        Some("c2lnbmF0dXJl"),
+        "signature mismatch"
+    );
+}
+
+#[rstest]
+#[case(Compression::None, DBLink::Symlink)]
+#[case(Compression::Gzip, DBLink::Copy)]
+#[case(Compression::Xz, DBLink::None)]
+#[case(Compression::Zstd, DBLink::Symlink)]
+fn must_build_with_options(#[case] compression: Compression, #[case] link: DBLink) {
+    let dir = tempdir().expect("unable to create temp dir");
+    let options = ArchiveOptions::new()
+        .compression(compression)
+        .level(Some(1))
+        .link(link)
+        .skip_files(link == DBLink::Copy);
+    DBBuilder::new()
+        .add_file(PathBuf::from("tests/pkgs/acl-2.3.1-1-x86_64.pkg.tar.zst"))
+        .build(BuildTarget::new(dir.path(), Some("test")).with_options(options))
+        .expect("unable to build db archive");
+
+    let archive = dir
+        .path()
+        .join(format!("test.db.{}", compression.extension()));
+    assert!(archive.exists(), "missing db archive");
+    let link_path = dir.path().join("test.db");
+    match link {
+        DBLink::Symlink => assert_eq!(
+            fs::read_link(&link_path).expect("db link isn't a symlink"),
+            PathBuf::from(format!("test.db.{}", compression.extension()))
+        ),
+        DBLink::Copy => assert!(!fs::symlink_metadata(&link_path)
+            .expect("missing db copy")
+            .file_type()
+            .is_symlink()),
+        DBLink::None => assert!(!link_path.exists(), "unexpected db link"),
+    }
+    assert_eq!(
+        dir.path()
+            .join(format!("test.files.{}", compression.extension()))
+            .exists(),
+        link != DBLink::Copy,
+        "files db presence mismatch"
+    );
+
+    let db = PacmanDB::open(dir.path(), "test", false).expect("unable to read db");
+    assert!(db.contains("acl"), "missing package");
+}
+
+#[rstest]
+#[case(Compression::Gzip, 10)]
+#[case(Compression::Xz, 10)]
+#[case(Compression::Zstd, 23)]
+fn must_reject_invalid_level(#[case] compression: Compression, #[case] level: u32) {
+    let dir = tempdir().expect("unable to create temp dir");
+    let options = ArchiveOptions::new()
+        .compression(compression)
+        .level(Some(level));
+    let result = DBBuilder::new()
+        .add_file(PathBuf::from("tests/pkgs/acl-2.3.1-1-x86_64.pkg.tar.zst"))
+        .build(BuildTarget::new(dir.path(), Some("test")).with_options(options));
+    assert!(
+        matches!(result, Err(Error::InvalidCompressionLevel(l)) if l == level),
+        "invalid level accepted"
+    );
+    assert!(
+        !dir.path()
+            .join(format!("test.db.{}", compression.extension()))
+            .exists(),
+        "db archive written with invalid level"
+    );
+}
@@ -1,3 +1,5 @@
 pub use pacman::*;
+pub use soname::*;
 
 mod pacman;
+mod soname;
@@ -0,0 +1,70 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::ops::{Bound, RangeBounds};
+
+use ranges::Ranges;
+
+use crate::types::*;
+
+// shared library dependency in makepkg's format, e.g. `libfoo.so=2-64`
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Soname {
+    pub name: String,
+    pub version: String,
+    pub bits: u8,
+}
+
+impl Soname {
+    // parse elf soname, e.g. `libfoo.so.2`
+    // returns None if the library isn't versioned
+    pub fn parse(soname: &str, is_64: bool) -> Option<Self> {
+        let (name, version) = soname.split_once(".so.")?;
+        Some(Self {
+            name: format!("{}.so", name),
+            version: version.to_string(),
+            bits: if is_64 { 64 } else { 32 },
+        })
+    }
+
+    // parse a provide/depend entry, e.g. `libfoo.so=2-64`
+    pub fn from_depend(dep: &Depend) -> Option<Self> {
+        if !dep.name.ends_with(".so") {
+            return None;
+        }
+        let range = dep.version.0.as_slice().first()?;
+        let version = match range.start_bound() {
+            Bound::Included(version) if range.is_singleton() => version.as_ref(),
+            _ => return None,
+        };
+        let (version, bits) = version.rsplit_once('-')?;
+        Some(Self {
+            name: dep.name.clone(),
+            version: version.to_string(),
+            bits: bits.parse().ok()?,
+        })
+    }
+
+    pub fn to_depend(&self) -> Depend {
+        Depend {
+            name: self.name.clone(),
+            version: DependVersion(Ranges::from(Version(format!(
+                "{}-{}",
+                self.version, self.bits
+            )))),
+        }
+    }
+}
+
+impl Display for Soname {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}-{}", self.name, self.version, self.bits)
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct PackageLinks {
+    // sonames the package links against, excluding its own libraries
+    pub needed: HashSet<Soname>,
+    // sonames of libraries shipped by the package
+    pub provided: HashSet<Soname>,
+}
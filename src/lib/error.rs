@@ -1,14 +1,18 @@
 use std::path::PathBuf;
 
 use online_scc_graph::Error as SCCGraphError;
-use rusoto_s3::{DeleteObjectError, GetObjectError, PutObjectError};
+use rusoto_s3::{
+    AbortMultipartUploadError, CompleteMultipartUploadError, CreateMultipartUploadError,
+    DeleteObjectError, GetObjectError, HeadObjectError, ListMultipartUploadsError,
+    ListObjectsV2Error, ListPartsError, PutObjectError, UploadPartError,
+};
 use thiserror::Error;
 
 use crate::types::*;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug, Eq, PartialEq, Error)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Error)]
 pub enum GpgError {
     #[error("Unknown fatal error")]
     Unknown,
@@ -66,6 +70,12 @@ pub enum CommandError {
     MkArchRoot,
     #[error("cp")]
     Cp,
+    #[error("git")]
+    Git,
+    #[error("hg")]
+    Hg,
+    #[error("svn")]
+    Svn,
 }
 
 #[derive(Debug, Error)]
@@ -86,6 +96,24 @@ pub enum S3Error {
     PutError(#[from] rusoto_core::RusotoError<PutObjectError>),
     #[error("delete error: {0}")]
     DeleteError(#[from] rusoto_core::RusotoError<DeleteObjectError>),
+    #[error("head error: {0}")]
+    HeadError(#[from] rusoto_core::RusotoError<HeadObjectError>),
+    #[error("list error: {0}")]
+    ListError(#[from] rusoto_core::RusotoError<ListObjectsV2Error>),
+    #[error("create multipart upload error: {0}")]
+    CreateMultipartError(#[from] rusoto_core::RusotoError<CreateMultipartUploadError>),
+    #[error("upload part error: {0}")]
+    UploadPartError(#[from] rusoto_core::RusotoError<UploadPartError>),
+    #[error("complete multipart upload error: {0}")]
+    CompleteMultipartError(#[from] rusoto_core::RusotoError<CompleteMultipartUploadError>),
+    #[error("list multipart uploads error: {0}")]
+    ListMultipartError(#[from] rusoto_core::RusotoError<ListMultipartUploadsError>),
+    #[error("list parts error: {0}")]
+    ListPartsError(#[from] rusoto_core::RusotoError<ListPartsError>),
+    #[error("abort multipart upload error: {0}")]
+    AbortMultipartError(#[from] rusoto_core::RusotoError<AbortMultipartUploadError>),
+    #[error("unexpected response: {0}")]
+    ResponseError(String),
     #[error("builder error: {0}")]
     BuilderError(String),
 }
@@ -94,6 +122,8 @@ pub enum S3Error {
 pub enum StorageError {
     #[error("invalid path: {0}")]
     InvalidPath(PathBuf),
+    #[error("invalid storage url: {0}")]
+    InvalidURL(String),
     #[error("io error: {0}")]
     IOError(#[from] std::io::Error),
     #[error("file exists: {0}")]
@@ -102,6 +132,12 @@ pub enum StorageError {
     FileNotExists(PathBuf),
     #[error("storage is in an inconsistent state")]
     Conflict,
+    #[error("lease is held by {0}")]
+    LeaseHeld(String),
+    #[error("checksum mismatch: {0}")]
+    ChecksumMismatch(PathBuf),
+    #[error("unsupported lock file version: {0}")]
+    UnsupportedLockVersion(u32),
     #[error("s3 error: {0}")]
     S3Error(#[from] S3Error),
     #[error("json error: {0}")]
@@ -112,12 +148,30 @@ pub enum StorageError {
 pub enum ParseError {
     #[error("pacman: {0}")]
     PacmanError(String),
+    #[error("desc: {0}")]
+    DescError(String),
     #[error("command execution failure: {0}")]
     CommandError(CommandError),
     #[error("io error: {0}")]
     IOError(#[from] std::io::Error),
 }
 
+#[derive(Debug, Error)]
+pub enum UpdateError {
+    #[error("unsupported vcs source: {0}")]
+    UnsupportedSource(String),
+    #[error("missing vcs source")]
+    MissingSource,
+    #[error("invalid package name: {0}")]
+    InvalidPackageName(String),
+    #[error("command execution failure: {0}")]
+    CommandError(#[from] CommandError),
+    #[error("http error: {0}")]
+    HTTPError(#[from] reqwest::Error),
+    #[error("io error: {0}")]
+    IOError(#[from] std::io::Error),
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Error)]
 pub enum DependencyError {
     #[error("missing dependency - {0}")]
@@ -154,10 +208,18 @@ pub enum Error {
     IOError(#[from] std::io::Error),
     #[error("unrecognized archive format")]
     ArchiveError,
+    #[error("unsupported archive format: {0}")]
+    UnsupportedArchive(String),
+    #[error("invalid compression level: {0}")]
+    InvalidCompressionLevel(u32),
     #[error("invalid package format")]
     PackageError,
+    #[error("command execution failure: {0}")]
+    CommandError(#[from] CommandError),
     #[error("storage error: {0}")]
     StorageError(#[from] StorageError),
     #[error("build error: {0}")]
     BuildError(#[from] BuildError),
+    #[error("update error: {0}")]
+    UpdateError(#[from] UpdateError),
 }
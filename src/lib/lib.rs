@@ -30,3 +30,4 @@ pub mod repository;
 pub mod resolver;
 pub mod storage;
 pub mod types;
+pub mod updater;
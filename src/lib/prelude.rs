@@ -6,3 +6,4 @@ pub use crate::repository::*;
 pub use crate::resolver::{types::*, PlanBuilder, TreeResolver};
 pub use crate::storage::{providers, types::*, StorageProvider};
 pub use crate::types::*;
+pub use crate::updater::*;
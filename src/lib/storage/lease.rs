@@ -0,0 +1,155 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::error::StorageError;
+use crate::utils::unix_timestamp;
+
+use super::transaction::delete_if_exists;
+use super::types::*;
+use super::StorageProvider;
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LeaseInfo {
+    pub owner: String,
+    pub token: String, // unique to each acquisition
+    pub expires: DateTime<Utc>,
+}
+
+impl LeaseInfo {
+    pub fn expired(&self) -> bool {
+        self.expires <= Utc::now()
+    }
+}
+
+// NOTE
+// An advisory lease stored in remote storage. S3 has no compare-and-swap, so two
+// writers acquiring an expired lease at the same moment may both succeed. The lease
+// is read back after being written to narrow this window.
+#[derive(Debug)]
+pub struct Lease {
+    key: PathBuf,
+    info: LeaseInfo,
+    ttl: Duration,
+}
+
+async fn read_lease<T: StorageProvider>(target: &T, key: &Path) -> Result<Option<LeaseInfo>> {
+    match target.get_file(key).await {
+        Ok(mut stream) => {
+            let mut buf = vec![];
+            stream.read_to_end(&mut buf).await?;
+            Ok(Some(serde_json::from_slice(&buf)?))
+        }
+        Err(StorageError::FileNotExists(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+async fn write_lease<T: StorageProvider>(target: &T, key: &Path, info: &LeaseInfo) -> Result<()> {
+    target
+        .replace_file(key, serde_json::to_vec(info)?.into())
+        .await
+}
+
+impl Lease {
+    // take the lease if it's free, expired, or held by the same owner
+    pub async fn acquire<T: StorageProvider>(
+        target: &T,
+        key: impl AsRef<Path>,
+        owner: &str,
+        ttl: Duration,
+    ) -> Result<Self> {
+        let key = key.as_ref().to_path_buf();
+        if let Some(current) = read_lease(target, &key).await? {
+            if !current.expired() && current.owner != owner {
+                return Err(StorageError::LeaseHeld(current.owner));
+            }
+        }
+
+        let token = Sha256::digest(format!(
+            "{}-{}-{}",
+            owner,
+            std::process::id(),
+            unix_timestamp()
+        ));
+        let lease = Self {
+            key,
+            info: LeaseInfo {
+                owner: owner.to_string(),
+                token: format!("{:x}", token),
+                expires: Utc::now() + ttl,
+            },
+            ttl,
+        };
+        write_lease(target, &lease.key, &lease.info).await?;
+        lease.check(target).await?;
+        Ok(lease)
+    }
+
+    // the lease currently stored, expired or not
+    pub async fn current<T: StorageProvider>(
+        target: &T,
+        key: impl AsRef<Path>,
+    ) -> Result<Option<LeaseInfo>> {
+        read_lease(target, key.as_ref()).await
+    }
+
+    pub const fn info(&self) -> &LeaseInfo {
+        &self.info
+    }
+
+    // ensure the lease is still held by us
+    pub async fn check<T: StorageProvider>(&self, target: &T) -> Result<()> {
+        match read_lease(target, &self.key).await? {
+            Some(current) if current.token == self.info.token && !current.expired() => Ok(()),
+            Some(current) if current.token != self.info.token => {
+                Err(StorageError::LeaseHeld(current.owner))
+            }
+            _ => Err(StorageError::Conflict),
+        }
+    }
+
+    // heartbeat, extend expiry of the lease
+    pub async fn renew<T: StorageProvider>(&mut self, target: &T) -> Result<()> {
+        self.check(target).await?;
+        self.info.expires = Utc::now() + self.ttl;
+        write_lease(target, &self.key, &self.info).await
+    }
+
+    // run the future while renewing the lease periodically
+    pub async fn hold<T, R, E>(
+        &mut self,
+        target: &T,
+        fut: impl Future<Output = std::result::Result<R, E>>,
+    ) -> std::result::Result<R, E>
+    where
+        T: StorageProvider,
+        E: From<StorageError>,
+    {
+        let period = (self.ttl / 3)
+            .to_std()
+            .unwrap_or_else(|_| std::time::Duration::from_secs(1));
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        tokio::pin!(fut);
+        loop {
+            tokio::select! {
+                result = &mut fut => return result,
+                _ = interval.tick() => self.renew(target).await?,
+            }
+        }
+    }
+
+    // release the lease if it's still held by us
+    pub async fn release<T: StorageProvider>(self, target: &T) -> Result<()> {
+        match read_lease(target, &self.key).await? {
+            Some(current) if current.token == self.info.token => {
+                delete_if_exists(target, &self.key).await
+            }
+            _ => Ok(()),
+        }
+    }
+}
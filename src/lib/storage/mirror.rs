@@ -0,0 +1,147 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use chrono::Duration;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::consts::LEASE_TTL;
+use crate::storage::lease::Lease;
+use crate::storage::pool::{default_owner, put_lock_file, read_lock_file, JOURNAL, LEASE};
+use crate::storage::transaction::{Txn, TxnAction};
+use crate::storage::StorageProvider;
+
+use super::types::*;
+
+lazy_static! {
+    // pacman databases, their links and signatures
+    static ref RE_DB: Regex = Regex::new(r"\.(db|files)(\.tar(\.[^.]+)?)?(\.sig)?$").unwrap();
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct MirrorReport {
+    pub copied: Vec<PathBuf>, // packages copied to dest
+    pub skipped: usize,       // packages already in dest
+    pub databases: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>, // databases only in dest
+}
+
+// packages missing in dest, or whose content differs
+fn stale_packages(source: &LockFile, dest: &LockFile) -> Vec<RemotePackageUnit> {
+    let published: HashSet<_> = dest
+        .packages
+        .iter()
+        .map(|unit| (&unit.key, &unit.sha256))
+        .collect();
+    source
+        .packages
+        .iter()
+        .filter(|unit| !published.contains(&(&unit.key, &unit.sha256)))
+        .cloned()
+        .collect()
+}
+
+// pacman databases in the root of a storage
+async fn list_databases<T: StorageProvider>(storage: &T) -> Result<Vec<PathBuf>> {
+    Ok(storage
+        .list(Path::new(""))
+        .await?
+        .into_iter()
+        .map(|meta| meta.path)
+        .filter(|path| RE_DB.is_match(&path.to_string_lossy()))
+        .collect())
+}
+
+// Replicate a repository published by `PackagePool` from `source` to `dest`.
+// Missing packages are copied first, then pacman databases, and the lock file is swapped last,
+// so that readers of dest never see a lock file referencing missing objects.
+// Leases of both storages are held, so that source isn't committed to while being read.
+// NOTE
+// Packages only in dest are left untouched, they are unreferenced after the sync and can be
+// removed by `PackagePool::gc`.
+pub async fn mirror<S, D>(source: &S, dest: &D) -> Result<MirrorReport>
+where
+    S: StorageProvider,
+    D: StorageProvider,
+{
+    let owner = format!("mirror-{}", default_owner());
+    let ttl = Duration::seconds(LEASE_TTL);
+    let mut dest_lease = Lease::acquire(dest, LEASE, &owner, ttl).await?;
+    let mut source_lease = match Lease::acquire(source, LEASE, &owner, ttl).await {
+        Ok(lease) => lease,
+        Err(e) => {
+            dest_lease.release(dest).await?;
+            return Err(e);
+        }
+    };
+    let result = source_lease
+        .hold(source, dest_lease.hold(dest, mirror_locked(source, dest)))
+        .await;
+    let source_released = source_lease.release(source).await;
+    let dest_released = dest_lease.release(dest).await;
+    let report = result?;
+    source_released?;
+    dest_released?;
+    Ok(report)
+}
+
+async fn mirror_locked<S, D>(source: &S, dest: &D) -> Result<MirrorReport>
+where
+    S: StorageProvider,
+    D: StorageProvider,
+{
+    // an interrupted commit to source is finished before it's read
+    Txn::recover(source, Path::new(JOURNAL)).await?;
+    Txn::recover(dest, Path::new(JOURNAL)).await?;
+    let source_lock = read_lock_file(source).await?;
+    let dest_lock = read_lock_file(dest).await?;
+
+    let mut report = MirrorReport::default();
+    let missing = stale_packages(&source_lock, &dest_lock);
+    report.skipped = source_lock.packages.len() - missing.len();
+    if source_lock == dest_lock {
+        // already in step
+        return Ok(report);
+    }
+
+    let mut txn = Txn::with_journal(JOURNAL);
+
+    // Packages unknown to dest are copied one by one, each fetched right before its upload.
+    // They aren't referenced until the lock file is swapped, so an interrupted sync only leaves
+    // unreferenced leftovers, which are overwritten next time.
+    // Packages referenced by dest with different content are replaced in the transaction.
+    let referenced: HashSet<_> = dest_lock.packages.iter().map(|unit| &unit.key).collect();
+    for unit in missing {
+        let data = source.get_file(&unit.key).await?;
+        if referenced.contains(&unit.key) {
+            txn.add(TxnAction::Replace(unit.key.clone(), data));
+        } else {
+            dest.replace_file(&unit.key, data).await?;
+        }
+        report.copied.push(unit.key);
+    }
+    txn.add(TxnAction::Barrier);
+
+    // databases are always copied, they are small
+    let databases = list_databases(source).await?;
+    for key in &databases {
+        txn.add(TxnAction::Replace(key.clone(), source.get_file(key).await?));
+    }
+    // databases removed from source, e.g. switched to another compression
+    let removed: Vec<_> = list_databases(dest)
+        .await?
+        .into_iter()
+        .filter(|key| !databases.contains(key))
+        .collect();
+    for key in &removed {
+        txn.add(TxnAction::Delete(key.clone()));
+    }
+    txn.add(TxnAction::Barrier);
+    report.databases = databases;
+    report.removed = removed;
+
+    put_lock_file(dest, &mut txn, &source_lock).await?;
+    txn.commit(dest).await?;
+
+    Ok(report)
+}
@@ -1,10 +1,16 @@
+pub use lease::Lease;
+pub use mirror::{mirror, MirrorReport};
 pub use pool::PackagePool;
 pub use providers::StorageProvider;
+pub use verify::{verify_repo, VerifyIssue, VerifyReport};
 
+pub mod lease;
+pub mod mirror;
 pub mod pool;
 pub mod providers;
 pub mod transaction;
 pub mod types;
+pub mod verify;
 
 #[cfg(test)]
 mod tests;
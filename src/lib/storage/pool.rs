@@ -1,12 +1,145 @@
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-use tokio::sync::Mutex;
+use chrono::{Duration, Utc};
+use itertools::Itertools;
+use lazy_static::lazy_static;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use tempfile::{tempdir, NamedTempFile};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{Mutex, MutexGuard};
 
+use crate::consts::{
+    CACHE_SAVE_INTERVAL, DOWNLOAD_RETRIES, GC_GRACE_PERIOD, LEASE_TTL, LOCK_FILE_VERSION,
+};
+use crate::database::{
+    ArchiveOptions, BuildTarget, Compression, DBBuilder, DBEditor, DBLink, PacmanDB, PacmanEntry,
+};
+use crate::error::{Error, StorageError};
+use crate::storage::lease::Lease;
 use crate::storage::transaction::{Txn, TxnAction};
 use crate::storage::StorageProvider;
 
 use super::types::*;
 
+lazy_static! {
+    // lock file, lease and pacman databases are never collected
+    pub(crate) static ref RE_PROTECTED: Regex =
+        Regex::new(r"(^|/)(index\.(lock(\.bak)?|lease)|txn\.journal(\.\d+|\.bak\.\d+\.\d+)?)$|\.(db|files)(\.tar(\.[^.]+)?)?(\.sig)?$")
+            .unwrap();
+}
+
+pub(crate) const LOCK_FILE: &str = "index.lock";
+pub(crate) const LOCK_FILE_BACKUP: &str = "index.lock.bak";
+pub(crate) const JOURNAL: &str = "txn.journal";
+pub(crate) const LEASE: &str = "index.lease";
+
+pub(crate) fn default_owner() -> String {
+    let user = users::get_current_username().map_or_else(
+        || String::from("unknown"),
+        |name| name.to_string_lossy().to_string(),
+    );
+    format!("{}-{}", user, std::process::id())
+}
+
+pub(crate) async fn get_optional<T: StorageProvider>(
+    storage: &T,
+    key: &Path,
+) -> Result<Option<Vec<u8>>> {
+    match storage.get_file(key).await {
+        Ok(mut stream) => {
+            let mut buf = vec![];
+            stream.read_to_end(&mut buf).await?;
+            Ok(Some(buf))
+        }
+        Err(StorageError::FileNotExists(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// read remote index.lock, a missing lock file is treated as an empty repo
+pub(crate) async fn read_lock_file<T: StorageProvider>(storage: &T) -> Result<LockFile> {
+    // the backup is readable while the lock file is being replaced
+    let data = match get_optional(storage, Path::new(LOCK_FILE)).await? {
+        Some(data) => Some(data),
+        None => get_optional(storage, Path::new(LOCK_FILE_BACKUP)).await?,
+    };
+    let lock_file = match data {
+        Some(data) => serde_json::from_slice::<LockFile>(&data)?,
+        None => LockFile::new(),
+    };
+    if lock_file.version != LOCK_FILE_VERSION {
+        return Err(StorageError::UnsupportedLockVersion(lock_file.version));
+    }
+    Ok(lock_file)
+}
+
+// add actions replacing remote lock file to txn
+pub(crate) async fn put_lock_file<T: StorageProvider>(
+    storage: &T,
+    txn: &mut Txn,
+    lock_file: &LockFile,
+) -> Result<()> {
+    if let Some(old_data) = get_optional(storage, Path::new(LOCK_FILE)).await? {
+        // keep a backup of the old lock file, the lock file itself is replaced atomically
+        txn.add(TxnAction::Replace(
+            PathBuf::from(LOCK_FILE_BACKUP),
+            ByteStream::from(old_data),
+        ));
+        txn.add(TxnAction::Barrier);
+    }
+    let lockfile_data = serde_json::to_vec(lock_file)?;
+    txn.add(TxnAction::Replace(
+        PathBuf::from(LOCK_FILE),
+        ByteStream::from(lockfile_data),
+    ));
+    Ok(())
+}
+
+// find remote `{repo}.{kind}` database, either its link or archive of any compression
+async fn find_remote_db<T: StorageProvider>(
+    storage: &T,
+    repo: &str,
+    kind: &str,
+) -> Result<Option<Vec<u8>>> {
+    let link = PathBuf::from(format!("{}.{}", repo, kind));
+    if let Some(data) = get_optional(storage, &link).await? {
+        return Ok(Some(data));
+    }
+    for compression in Compression::ALL {
+        let archive = PathBuf::from(format!("{}.{}.{}", repo, kind, compression.extension()));
+        if let Some(data) = get_optional(storage, &archive).await? {
+            return Ok(Some(data));
+        }
+    }
+    Ok(None)
+}
+
+// run blocking work, e.g. hashing & indexing packages, off the async runtime
+async fn blocking<R, F>(f: F) -> std::result::Result<R, Error>
+where
+    R: Send + 'static,
+    F: FnOnce() -> std::result::Result<R, Error> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+}
+
+// generate database entry of a package stored under `key`
+async fn index_package(
+    path: PathBuf,
+    key: &Path,
+) -> std::result::Result<(PacmanEntry, Vec<String>), Error> {
+    let (mut desc, files) = blocking(move || DBBuilder::index_package(&path)).await?;
+    // packages are stored under their canonicalized names
+    desc.file_name = key.to_string_lossy().to_string();
+    Ok((desc, files))
+}
+
 pub struct PackagePool<T: StorageProvider> {
     remote: T,
     // remote storage
@@ -16,7 +149,20 @@ pub struct PackagePool<T: StorageProvider> {
     // meta->key
     local_map: Mutex<MetaKeyMap>,
     // meta->filename
-    stage_map: MetaKeyMap, // meta->path
+    digests: Mutex<DigestMap>,
+    // key->sha256, locked after remote_map
+    cache_index: Mutex<CacheIndex>,
+    // persistent index of local cache, locked after local_map
+    cache_loaded: AtomicBool, // cache index is loaded on first use
+    cache_hits: AtomicUsize,  // cache hits not saved into cache index yet
+    cache_verified: Mutex<HashSet<PathBuf>>,
+    // cached files known to match their digests, locked after local_map
+    cache_limit: Option<u64>, // local cache is unbounded if None
+    stage_map: MetaKeyMap,    // meta->path
+    repo: Option<String>,     // pacman database isn't published if None
+    db_options: ArchiveOptions,
+    gc_grace: Duration, // unreferenced files newer than this are kept by gc
+    owner: String,      // owner name of the remote lease
 }
 
 impl<T: StorageProvider> PackagePool<T> {
@@ -26,22 +172,457 @@ impl<T: StorageProvider> PackagePool<T> {
             local,
             remote_map: Mutex::new(Default::default()),
             local_map: Mutex::new(Default::default()),
+            digests: Mutex::new(Default::default()),
+            cache_index: Mutex::new(Default::default()),
+            cache_loaded: AtomicBool::new(false),
+            cache_hits: AtomicUsize::new(0),
+            cache_verified: Mutex::new(Default::default()),
+            cache_limit: None,
             stage_map: Default::default(),
+            repo: None,
+            db_options: Default::default(),
+            gc_grace: Duration::seconds(GC_GRACE_PERIOD),
+            owner: default_owner(),
+        }
+    }
+
+    // create a pool and load published packages from remote
+    pub async fn open(remote: T, local: PathBuf) -> Result<Self> {
+        let pool = Self::new(remote, local);
+        pool.sync().await?;
+        Ok(pool)
+    }
+
+    // Recover an interrupted transaction, unless the lease is held.
+    // A journal under a live lease belongs to a transaction in progress.
+    async fn recover(&self) -> Result<()> {
+        if let Some(current) = Lease::current(&self.remote, LEASE).await? {
+            if !current.expired() {
+                return Ok(());
+            }
+        }
+        let mut lease = match Lease::acquire(
+            &self.remote,
+            LEASE,
+            &self.owner,
+            Duration::seconds(LEASE_TTL),
+        )
+        .await
+        {
+            Ok(lease) => lease,
+            Err(StorageError::LeaseHeld(_)) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let result = lease
+            .hold(&self.remote, Txn::recover(&self.remote, Path::new(JOURNAL)))
+            .await;
+        let released = lease.release(&self.remote).await;
+        result?;
+        released
+    }
+
+    // load remote index.lock, interrupted transactions are recovered first
+    // the lock file is still readable while others are committing
+    pub async fn sync(&self) -> Result<()> {
+        self.recover().await?;
+        let lock_file = read_lock_file(&self.remote).await?;
+
+        let mut remote_map = self.remote_map.lock().await;
+        let mut digests = self.digests.lock().await;
+        let mut local_map = self.local_map.lock().await;
+        *remote_map = MetaKeyMap::from(&lock_file);
+        *digests = DigestMap::from(&lock_file);
+
+        // reuse packages already downloaded into local cache
+        let mut cache_index = self.cache_index.lock().await;
+        *cache_index = CacheIndex::load(&self.local).await?;
+        self.cache_loaded.store(true, Ordering::Release);
+        // files on disk may have been changed meanwhile
+        self.cache_verified.lock().await.clear();
+        *local_map = remote_map
+            .iter()
+            .filter(|(_, key)| cache_index.contains(key))
+            .map(|(meta, key)| (meta.clone(), key.clone()))
+            .collect();
+        self.shrink_cache(&mut local_map, &mut cache_index, None)
+            .await
+    }
+
+    // limit total size of local cache, least recently used files are evicted first
+    // NOTE
+    // paths returned by `get` may be evicted by later calls
+    pub fn with_cache_limit(mut self, limit: u64) -> Self {
+        self.cache_limit = Some(limit);
+        self
+    }
+
+    // a pool created by `new` hasn't loaded the cache index, and mustn't overwrite it
+    async fn lock_cache_index(&self) -> Result<MutexGuard<'_, CacheIndex>> {
+        let mut cache_index = self.cache_index.lock().await;
+        if !self.cache_loaded.load(Ordering::Acquire) {
+            *cache_index = CacheIndex::load(&self.local).await?;
+            self.cache_loaded.store(true, Ordering::Release);
+        }
+        Ok(cache_index)
+    }
+
+    // save access order of cache hits which isn't written to cache index yet
+    pub async fn flush(&self) -> Result<()> {
+        let cache_index = self.cache_index.lock().await;
+        if self.cache_hits.swap(0, Ordering::AcqRel) > 0 {
+            cache_index.save(&self.local).await?;
+        }
+        Ok(())
+    }
+
+    // evict files over cache limit and save cache index
+    async fn shrink_cache(
+        &self,
+        local_map: &mut MetaKeyMap,
+        cache_index: &mut CacheIndex,
+        keep: Option<&Path>,
+    ) -> Result<()> {
+        if let Some(limit) = self.cache_limit {
+            let evicted: HashSet<_> = cache_index.evict(limit, keep).into_iter().collect();
+            for key in &evicted {
+                match tokio::fs::remove_file(self.local.join(key)).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => (),
+                }
+            }
+            local_map.retain(|_, key| !evicted.contains(key));
+            self.cache_verified
+                .lock()
+                .await
+                .retain(|key| !evicted.contains(key));
+        }
+        self.cache_hits.store(0, Ordering::Release);
+        cache_index.save(&self.local).await
+    }
+
+    // publish pacman database `{repo}.db` & `{repo}.files` on commit
+    pub fn with_repo(mut self, repo: &str) -> Self {
+        self.repo = Some(repo.to_string());
+        self
+    }
+
+    // compression & signing of published databases, links are always copied
+    pub fn with_db_options(mut self, options: ArchiveOptions) -> Self {
+        self.db_options = options;
+        self
+    }
+
+    // files of in-flight commits aren't referenced yet, so gc only deletes older files
+    pub fn with_gc_grace(mut self, grace: Duration) -> Self {
+        self.gc_grace = grace;
+        self
+    }
+
+    // owner name written into the remote lease, `{user}-{pid}` by default
+    pub fn with_owner(mut self, owner: &str) -> Self {
+        self.owner = owner.to_string();
+        self
+    }
+
+    // run remote writes while holding the lease, so that concurrent writers are rejected
+    async fn with_lease<R>(
+        &self,
+        fut: impl Future<Output = std::result::Result<R, Error>>,
+    ) -> std::result::Result<R, Error> {
+        let mut lease = Lease::acquire(
+            &self.remote,
+            LEASE,
+            &self.owner,
+            Duration::seconds(LEASE_TTL),
+        )
+        .await?;
+        let result = lease.hold(&self.remote, fut).await;
+        let released = lease.release(&self.remote).await;
+        let value = result?;
+        released?;
+        Ok(value)
+    }
+
+    // index a published package, its signature is fetched too so that it's embedded
+    async fn index_remote(
+        &self,
+        key: &Path,
+        sha256: Option<&str>,
+    ) -> std::result::Result<(PacmanEntry, Vec<String>), Error> {
+        let data = self.download(key, sha256).await?;
+        let mut sig_path = data.path().as_os_str().to_os_string();
+        sig_path.push(".sig");
+        let mut sig_key = key.as_os_str().to_os_string();
+        sig_key.push(".sig");
+        let sig = get_optional(&self.remote, Path::new(&sig_key)).await?;
+        if let Some(sig) = &sig {
+            tokio::fs::write(&sig_path, sig).await?;
+        }
+
+        let result = index_package(data.path().to_path_buf(), key).await;
+        if sig.is_some() {
+            tokio::fs::remove_file(&sig_path).await?;
+        }
+        result
+    }
+
+    // update remote database with entries of staged packages
+    // returns actions to replace database files
+    async fn update_db(
+        &self,
+        repo: &str,
+        staged: Vec<(PacmanEntry, Vec<String>)>,
+        published: &MetaKeyMap,
+        digests: &DigestMap,
+    ) -> std::result::Result<Vec<TxnAction>, Error> {
+        let db = find_remote_db(&self.remote, repo, "db").await?;
+        let files = find_remote_db(&self.remote, repo, "files").await?;
+
+        let mut editor = match db {
+            Some(db) => DBEditor::from_archives(&*db, files.as_deref())?,
+            None => {
+                // pools created by older versions have published packages but no database,
+                // so the newest version of each package is indexed from remote
+                let mut editor = DBEditor::new();
+                let staged_names: HashSet<_> =
+                    staged.iter().map(|(desc, _)| desc.name.as_str()).collect();
+                let newest = published
+                    .iter()
+                    .filter(|(meta, _)| !staged_names.contains(meta.name.as_str()))
+                    .into_group_map_by(|(meta, _)| meta.name.as_str())
+                    .into_values()
+                    .filter_map(|units| {
+                        units
+                            .into_iter()
+                            .max_by(|a, b| a.0.version.cmp(&b.0.version))
+                    })
+                    .map(|(_, key)| key)
+                    .sorted();
+                for key in newest {
+                    let (desc, files) = self
+                        .index_remote(key, digests.get(key).map(String::as_str))
+                        .await?;
+                    editor.add_entry(&desc, &files)?;
+                }
+                editor
+            }
+        };
+        for (desc, files) in &staged {
+            editor.add_entry(desc, files)?;
+        }
+
+        // storage backends may not support symlinks, so links are copied
+        let workdir = tempdir()?;
+        editor.build(
+            BuildTarget::new(workdir.path(), Some(repo))
+                .with_options(self.db_options.clone().link(DBLink::Copy)),
+        )?;
+
+        // archives, links and signatures
+        let mut names = vec![];
+        let mut entries = tokio::fs::read_dir(workdir.path()).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            names.push(PathBuf::from(entry.file_name()));
+        }
+        names.sort();
+        let mut actions = vec![];
+        for name in names {
+            let data = tokio::fs::read(workdir.path().join(&name)).await?;
+            actions.push(TxnAction::Replace(name, ByteStream::from(data)));
+        }
+        Ok(actions)
+    }
+
+    // drop packages according to retention policy, and delete files not referenced by lock file
+    // nothing is changed in dry-run mode
+    pub async fn gc(
+        &self,
+        policy: RetentionPolicy,
+        dry_run: bool,
+    ) -> std::result::Result<GcReport, Error> {
+        if dry_run {
+            self.gc_locked(policy, true).await
+        } else {
+            self.with_lease(self.gc_locked(policy, false)).await
         }
     }
 
+    async fn gc_locked(
+        &self,
+        policy: RetentionPolicy,
+        dry_run: bool,
+    ) -> std::result::Result<GcReport, Error> {
+        // hold the lock until deletion is done, so that nothing new is referenced meanwhile
+        let mut remote_map = self.remote_map.lock().await;
+        let mut digests = self.digests.lock().await;
+        let mut local_map = self.local_map.lock().await;
+
+        // the lease is held unless in dry-run mode
+        if !dry_run {
+            Txn::recover(&self.remote, Path::new(JOURNAL)).await?;
+        }
+
+        // packages may have been published by others since last sync
+        let latest = read_lock_file(&self.remote).await?;
+        *remote_map = MetaKeyMap::from(&latest);
+        *digests = DigestMap::from(&latest);
+
+        // packages referenced by current database must be kept
+        let mut db_files = HashSet::new();
+        if let Some(repo) = &self.repo {
+            if let Some(db) = find_remote_db(&self.remote, repo, "db").await? {
+                db_files.extend(
+                    PacmanDB::from_reader(&*db)?
+                        .into_iter()
+                        .map(|entry| PathBuf::from(entry.desc.file_name)),
+                );
+            }
+        }
+
+        let files = self.remote.list(Path::new("")).await?;
+        let mtimes: HashMap<_, _> = files
+            .iter()
+            .map(|meta| (meta.path.clone(), meta.mtime))
+            .collect();
+
+        // apply retention policy on each package
+        let mut expired = vec![];
+        let grouped = remote_map
+            .iter()
+            .into_group_map_by(|(meta, _)| meta.name.clone());
+        for (_, mut versions) in grouped {
+            versions.sort_by(|(a, _), (b, _)| b.version.cmp(&a.version));
+            // the newest version is always kept
+            for (idx, (meta, key)) in versions.into_iter().enumerate().skip(1) {
+                let expire = match policy {
+                    RetentionPolicy::KeepAll => false,
+                    RetentionPolicy::KeepLast(n) => idx >= n,
+                    RetentionPolicy::KeepFor(duration) => mtimes
+                        .get(key)
+                        .copied()
+                        .flatten()
+                        .map_or(false, |mtime| Utc::now() - mtime > duration),
+                };
+                if expire && !db_files.contains(key) {
+                    expired.push(meta.clone());
+                }
+            }
+        }
+        let mut new_map = remote_map.clone();
+        for meta in &expired {
+            new_map.remove(meta);
+        }
+
+        // files not referenced by the new lock file
+        let referenced: HashSet<_> = new_map.values().cloned().collect();
+        let mut new_digests = digests.clone();
+        new_digests.retain(|key, _| referenced.contains(key));
+        let is_kept = |path: &Path| referenced.contains(path) || db_files.contains(path);
+        let grace_start = Utc::now() - self.gc_grace;
+        let deleted = files
+            .into_iter()
+            .filter(|meta| meta.mtime.map_or(true, |mtime| mtime < grace_start))
+            .map(|meta| meta.path)
+            .filter(|path| !is_kept(path))
+            .filter(|path| {
+                // detached signature of a kept package
+                path.to_str()
+                    .and_then(|path| path.strip_suffix(".sig"))
+                    .map_or(true, |pkg| !is_kept(Path::new(pkg)))
+            })
+            .filter(|path| !RE_PROTECTED.is_match(&path.to_string_lossy()))
+            .collect_vec();
+
+        if !dry_run {
+            let mut txn = Txn::with_journal(JOURNAL);
+            // lock file mustn't reference a deleted object, so it's updated first
+            if !expired.is_empty() {
+                put_lock_file(
+                    &self.remote,
+                    &mut txn,
+                    &LockFile::from(&new_map).with_digests(&new_digests),
+                )
+                .await?;
+                txn.add(TxnAction::Barrier);
+            }
+            for path in &deleted {
+                txn.add(TxnAction::Delete(path.clone()));
+            }
+            txn.commit(&self.remote).await?;
+
+            // drop expired packages from local cache
+            let mut cache_index = self.lock_cache_index().await?;
+            for meta in &expired {
+                if let Some(key) = local_map.remove(meta) {
+                    tokio::fs::remove_file(self.local.join(&key)).await?;
+                    cache_index.remove(&key);
+                }
+            }
+            cache_index.save(&self.local).await?;
+            *remote_map = new_map;
+            *digests = new_digests;
+        }
+
+        Ok(GcReport { expired, deleted })
+    }
+
     // generate & commit transaction to remote, and clear stage area
-    pub async fn commit(&mut self) -> Result<()> {
-        let mut txn = Txn::new();
+    // fails with `StorageError::Conflict` if a staged package has been published with
+    // different content by others
+    pub async fn commit(&mut self) -> std::result::Result<(), Error> {
+        self.with_lease(self.commit_locked()).await?;
+
+        // update stage map
+        self.stage_map.clear();
+
+        Ok(())
+    }
+
+    async fn commit_locked(&self) -> std::result::Result<(), Error> {
+        // an interrupted transaction would block this one
+        Txn::recover(&self.remote, Path::new(JOURNAL)).await?;
+        let mut txn = Txn::with_journal(JOURNAL);
         // locking remote and local maps, preventing inconsistency when getting file
         let mut remote_map = self.remote_map.lock().await;
+        let mut digests = self.digests.lock().await;
         let mut local_map = self.local_map.lock().await;
 
+        // merge into the latest lock file, others may have committed since last sync
+        let latest = read_lock_file(&self.remote).await?;
+        let mut new_map = MetaKeyMap::from(&latest);
+        let mut new_digests = DigestMap::from(&latest);
+
+        let mut fresh = vec![];
         for (meta, path) in &self.stage_map {
             let unit = LocalPackageUnit::new(meta, path);
-            // update remote & local maps
             let key = PathBuf::from(unit.canonicalize_filename());
-            remote_map.insert(meta.clone(), key.clone());
+            // each package is read once, for both its digest and database entry
+            let (sha256, entry) = if self.repo.is_some() {
+                let (desc, files) = index_package(path.clone(), &key).await?;
+                (desc.sha256_sum.clone(), Some((desc, files)))
+            } else {
+                let path = path.clone();
+                let checksums =
+                    blocking(move || DBBuilder::checksum(std::fs::File::open(path)?)).await?;
+                (checksums.sha256, None)
+            };
+            if let Some(published) = new_map.get(meta) {
+                // only identical packages can be merged
+                let same_digest = new_digests
+                    .get(published)
+                    .map_or(true, |digest| digest == &sha256);
+                if published != &key || !same_digest {
+                    return Err(StorageError::Conflict.into());
+                }
+                continue;
+            }
+            new_map.insert(meta.clone(), key.clone());
+            new_digests.insert(key.clone(), sha256);
+            fresh.push((meta, path, key, entry));
+        }
+
+        let mut staged = vec![];
+        for (meta, path, key, entry) in fresh {
             local_map.insert(meta.clone(), key.clone());
 
             // put package transaction
@@ -50,36 +631,133 @@ impl<T: StorageProvider> PackagePool<T> {
             // pre-cache package
             // file will be copied into dest before txn is committed
             // this is safe because we locked local_map
-            tokio::fs::copy(path, self.local.join(key)).await?;
+            let size = self.copy_to_cache(path, &key).await?;
+            self.lock_cache_index().await?.insert(&key, size);
+            self.cache_verified.lock().await.insert(key.clone());
+
+            staged.extend(entry);
+        }
+        {
+            let mut cache_index = self.lock_cache_index().await?;
+            self.shrink_cache(&mut local_map, &mut cache_index, None)
+                .await?;
         }
 
         // ensure all packages are saved
         txn.add(TxnAction::Barrier);
 
+        // update pacman database after packages are available
+        if let Some(repo) = &self.repo {
+            for action in self.update_db(repo, staged, &new_map, &new_digests).await? {
+                txn.add(action);
+            }
+            txn.add(TxnAction::Barrier);
+        }
+
         // generate & put lock file
-        txn.add(TxnAction::Delete(PathBuf::from("index.lock")));
-        txn.add(TxnAction::Barrier); // ensure order (s3 doesn't support atomic renaming, so...)
-        let new_lock_file = LockFile::from(&*remote_map);
-        let lockfile_data = serde_json::to_vec(&new_lock_file)?;
-        txn.add(TxnAction::Put(
-            PathBuf::from("index.lock"),
-            ByteStream::from(lockfile_data),
-        ));
+        put_lock_file(
+            &self.remote,
+            &mut txn,
+            &LockFile::from(&new_map).with_digests(&new_digests),
+        )
+        .await?;
 
         // commit transaction
         txn.commit(&self.remote).await?;
 
-        // update stage map
-        self.stage_map.clear();
+        // update remote map
+        *remote_map = new_map;
+        *digests = new_digests;
 
         Ok(())
     }
 
+    // Copy a file into local cache through a temp file, so that a crash never leaves a
+    // truncated file under its cache name. Returns the size of the file.
+    async fn copy_to_cache(&self, path: &Path, key: &Path) -> Result<u64> {
+        let temp = NamedTempFile::new_in(&self.local)?;
+        let mut dest = tokio::fs::File::from_std(temp.reopen()?);
+        let size = tokio::io::copy(&mut tokio::fs::File::open(path).await?, &mut dest).await?;
+        dest.sync_all().await?;
+        temp.persist(self.local.join(key)).map_err(|e| e.error)?;
+        Ok(size)
+    }
+
+    // Check a cached file against its digest once, files adopted from disk by
+    // `CacheIndex::load` may be left corrupt by a crash.
+    async fn verify_cached(&self, key: &Path) -> Result<bool> {
+        if self.cache_verified.lock().await.contains(key) {
+            return Ok(true);
+        }
+        // lock files written by older versions carry no digest
+        let sha256 = match self.digests.lock().await.get(key).cloned() {
+            Some(sha256) => sha256,
+            None => return Ok(true),
+        };
+        let path = self.local.join(key);
+        let digest = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+            Ok(format!("{:x}", hasher.finalize()))
+        })
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))??;
+        if digest != sha256 {
+            return Ok(false);
+        }
+        self.cache_verified.lock().await.insert(key.to_path_buf());
+        Ok(true)
+    }
+
     // stage built package
     pub fn stage(&mut self, unit: LocalPackageUnit) {
         self.stage_map.insert(unit.meta, unit.path);
     }
 
+    // Download a file into a temp file in local cache dir, hashing while streaming.
+    // The file is rejected if its sha256 doesn't match, so a corrupt download never
+    // lands in local cache.
+    async fn download_once(&self, key: &Path, sha256: Option<&str>) -> Result<NamedTempFile> {
+        let mut data = self.remote.get_file(key).await?;
+        let temp = NamedTempFile::new_in(&self.local)?;
+        let mut dest = tokio::fs::File::from_std(temp.reopen()?);
+
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 65536];
+        loop {
+            let n = data.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            dest.write_all(&buf[..n]).await?;
+        }
+        dest.sync_all().await?;
+
+        // lock files written by older versions carry no digest
+        match sha256 {
+            Some(sha256) if format!("{:x}", hasher.finalize()) != sha256 => {
+                Err(StorageError::ChecksumMismatch(key.to_path_buf()))
+            }
+            _ => Ok(temp),
+        }
+    }
+
+    // retry on corrupt or interrupted downloads
+    async fn download(&self, key: &Path, sha256: Option<&str>) -> Result<NamedTempFile> {
+        let mut retries = DOWNLOAD_RETRIES;
+        loop {
+            match self.download_once(key, sha256).await {
+                Err(StorageError::ChecksumMismatch(_) | StorageError::IOError(_))
+                    if retries > 0 =>
+                {
+                    retries -= 1
+                }
+                result => return result,
+            }
+        }
+    }
+
     // get package path (first from stage, then local cache, then remote)
     pub async fn get(&mut self, meta: &PackageMeta) -> Result<Option<PathBuf>> {
         if let Some(path) = self.stage_map.get(meta) {
@@ -87,16 +765,36 @@ impl<T: StorageProvider> PackagePool<T> {
             return Ok(Some(path.clone()));
         }
 
-        let maybe_local_filename = self.local_map.lock().await.get(meta).cloned();
-        if let Some(filename) = maybe_local_filename {
-            // exists in local cache
-            return Ok(Some(self.local.join(filename)));
+        {
+            let mut local_map = self.local_map.lock().await;
+            if let Some(filename) = local_map.get(meta).cloned() {
+                if self.verify_cached(&filename).await? {
+                    // exists in local cache
+                    let mut cache_index = self.lock_cache_index().await?;
+                    cache_index.touch(&filename);
+                    // access order is saved in batches, losing some only affects eviction order
+                    if self.cache_hits.fetch_add(1, Ordering::AcqRel) + 1 >= CACHE_SAVE_INTERVAL {
+                        self.cache_hits.store(0, Ordering::Release);
+                        cache_index.save(&self.local).await?;
+                    }
+                    return Ok(Some(self.local.join(filename)));
+                }
+
+                // corrupt file in local cache, download it again
+                local_map.remove(meta);
+                self.lock_cache_index().await?.remove(&filename);
+                match tokio::fs::remove_file(self.local.join(&filename)).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => (),
+                }
+            }
         }
 
         let maybe_remote_key = self.remote_map.lock().await.get(meta).cloned();
         return if let Some(key) = maybe_remote_key {
             // optimistic lock: first try to download
-            let data = self.remote.get_file(&key).await?;
+            let sha256 = self.digests.lock().await.get(&key).cloned();
+            let data = self.download(&key, sha256.as_deref()).await?;
             let mut local_map = self.local_map.lock().await;
             if let Some(filename) = local_map.get(meta) {
                 // conflict, take the previously downloaded file
@@ -106,9 +804,16 @@ impl<T: StorageProvider> PackagePool<T> {
                 // NOTE
                 // assume that remote file is at root directory
                 let local_path = self.local.join(&key); // take its remote key as cache name
-                data.into_file(&local_path).await?;
+                let size = data.as_file().metadata()?.len();
+                data.persist(&local_path).map_err(|e| e.error)?;
 
-                local_map.insert(meta.clone(), key); // update local map
+                // update local map & cache index
+                local_map.insert(meta.clone(), key.clone());
+                self.cache_verified.lock().await.insert(key.clone());
+                let mut cache_index = self.lock_cache_index().await?;
+                cache_index.insert(&key, size);
+                self.shrink_cache(&mut local_map, &mut cache_index, Some(&key))
+                    .await?;
 
                 Ok(Some(local_path))
             }
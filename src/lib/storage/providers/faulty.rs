@@ -0,0 +1,222 @@
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::AsyncReadExt;
+
+use crate::error::StorageError;
+use crate::storage::types::*;
+
+use super::Result;
+use super::StorageProvider;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Operation {
+    Get,
+    Stat,
+    Put,
+    Delete,
+    List,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Fault {
+    // fail without touching the inner storage
+    Fail,
+    // delay the operation, other rules still apply
+    Latency(Duration),
+    // write only the first n bytes then fail, like a crash during upload (put only)
+    PartialWrite(usize),
+    // return only the first half of the file, like a broken download (get only)
+    Corrupt,
+}
+
+// Inject `fault` into matching operations.
+// The first `skip` matches are let through, then the fault is injected `times` times.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct FaultRule {
+    operation: Operation,
+    fault: Fault,
+    key: Option<PathBuf>, // match any key if None
+    skip: usize,
+    times: Option<usize>, // forever if None
+}
+
+impl FaultRule {
+    pub const fn new(operation: Operation, fault: Fault) -> Self {
+        Self {
+            operation,
+            fault,
+            key: None,
+            skip: 0,
+            times: None,
+        }
+    }
+    setter_copy!(skip, usize);
+    setter_copy!(times, Option<usize>);
+
+    pub fn key(mut self, key: impl AsRef<Path>) -> Self {
+        self.key = Some(key.as_ref().to_path_buf());
+        self
+    }
+
+    fn matches(&self, operation: Operation, path: &Path) -> bool {
+        self.operation == operation && self.key.as_ref().map_or(true, |key| key == path)
+    }
+}
+
+fn injected(path: &Path) -> StorageError {
+    StorageError::IOError(std::io::Error::new(
+        ErrorKind::Other,
+        format!("injected fault: {}", path.display()),
+    ))
+}
+
+// Wraps another provider and injects configured faults, for deterministic tests.
+pub struct FaultyStorage<T: StorageProvider> {
+    inner: T,
+    rules: Mutex<Vec<(FaultRule, usize)>>, // rule & matched count
+}
+
+impl<T: StorageProvider> FaultyStorage<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            rules: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn with_fault(self, rule: FaultRule) -> Self {
+        self.add_fault(rule);
+        self
+    }
+
+    // faults can be added after the storage is handed over
+    pub fn add_fault(&self, rule: FaultRule) {
+        self.rules.lock().unwrap().push((rule, 0));
+    }
+
+    pub fn clear_faults(&self) {
+        self.rules.lock().unwrap().clear();
+    }
+
+    pub const fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    // collect faults to inject into this operation
+    fn faults(&self, operation: Operation, path: &Path) -> Vec<Fault> {
+        let mut rules = self.rules.lock().unwrap();
+        rules
+            .iter_mut()
+            .filter(|(rule, _)| rule.matches(operation, path))
+            .filter_map(|(rule, count)| {
+                *count += 1;
+                let active = *count > rule.skip
+                    && rule.times.map_or(true, |times| *count <= rule.skip + times);
+                active.then(|| rule.fault)
+            })
+            .collect()
+    }
+
+    async fn put_inner(&self, path: &Path, data: ByteStream, replace: bool) -> Result<()> {
+        if replace {
+            self.inner.replace_file(path, data).await
+        } else {
+            self.inner.put_file(path, data).await
+        }
+    }
+
+    async fn put(&self, path: &Path, mut data: ByteStream, replace: bool) -> Result<()> {
+        match self.inject(Operation::Put, path).await {
+            Some(Fault::PartialWrite(len)) => {
+                let mut buf = vec![];
+                (&mut data).take(len as u64).read_to_end(&mut buf).await?;
+                self.put_inner(path, ByteStream::from(buf), replace).await?;
+                Err(injected(path))
+            }
+            Some(_) => Err(injected(path)),
+            None => self.put_inner(path, data, replace).await,
+        }
+    }
+
+    async fn get(&self, path: &Path, range: Option<Range<u64>>) -> Result<ByteStream> {
+        let fault = self.inject(Operation::Get, path).await;
+        if matches!(fault, Some(fault) if fault != Fault::Corrupt) {
+            return Err(injected(path));
+        }
+        let mut data = match range {
+            Some(range) => self.inner.get_range(path, range).await?,
+            None => self.inner.get_file(path).await?,
+        };
+        if fault.is_none() {
+            return Ok(data);
+        }
+        let mut buf = vec![];
+        data.read_to_end(&mut buf).await?;
+        buf.truncate(buf.len() / 2);
+        Ok(ByteStream::from(buf))
+    }
+
+    // sleep for latencies, and return the first failure if any
+    async fn inject(&self, operation: Operation, path: &Path) -> Option<Fault> {
+        let mut failure = None;
+        for fault in self.faults(operation, path) {
+            match fault {
+                Fault::Latency(duration) => tokio::time::sleep(duration).await,
+                _ => {
+                    failure.get_or_insert(fault);
+                }
+            }
+        }
+        failure
+    }
+}
+
+#[async_trait]
+impl<T: StorageProvider> StorageProvider for FaultyStorage<T> {
+    async fn get_file(&self, path: &Path) -> Result<ByteStream> {
+        self.get(path, None).await
+    }
+
+    async fn get_range(&self, path: &Path, range: Range<u64>) -> Result<ByteStream> {
+        self.get(path, Some(range)).await
+    }
+
+    async fn stat(&self, path: &Path) -> Result<FileMeta> {
+        match self.inject(Operation::Stat, path).await {
+            Some(_) => Err(injected(path)),
+            None => self.inner.stat(path).await,
+        }
+    }
+
+    async fn put_file(&self, path: &Path, data: ByteStream) -> Result<()> {
+        self.put(path, data, false).await
+    }
+
+    // replacing is a put, so put faults apply
+    async fn replace_file(&self, path: &Path, data: ByteStream) -> Result<()> {
+        self.put(path, data, true).await
+    }
+
+    async fn delete_file(&self, path: &Path) -> Result<()> {
+        match self.inject(Operation::Delete, path).await {
+            Some(_) => Err(injected(path)),
+            None => self.inner.delete_file(path).await,
+        }
+    }
+
+    async fn list(&self, prefix: &Path) -> Result<Vec<FileMeta>> {
+        match self.inject(Operation::List, prefix).await {
+            Some(_) => Err(injected(prefix)),
+            None => self.inner.list(prefix).await,
+        }
+    }
+}
@@ -1,8 +1,10 @@
-use std::convert::TryFrom;
-use std::io::Cursor;
+use std::fs::Metadata;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 
 use async_trait::async_trait;
+use chrono::DateTime;
 use tempfile::NamedTempFile;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
@@ -14,6 +16,12 @@ use crate::storage::types::*;
 use super::Result;
 use super::{get_fullpath, StorageProvider};
 
+// prefix of files being written
+// NOTE
+// They are listed like other files, so that leftovers of interrupted writes can be collected
+// by `PackagePool::gc`, whose grace period protects writes in progress.
+const TEMP_PREFIX: &str = ".partial-";
+
 pub struct FSStorage {
     base: PathBuf,
     memory_limit: u64,
@@ -46,6 +54,70 @@ async fn file_exists(path: &Path) -> bool {
         .unwrap_or(false)
 }
 
+// run blocking filesystem calls off the async runtime
+async fn blocking<R, F>(f: F) -> std::io::Result<R>
+where
+    R: Send + 'static,
+    F: FnOnce() -> std::io::Result<R> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| std::io::Error::new(ErrorKind::Other, e))?
+}
+
+// Write data into a temp file beside the destination and fsync it, so that it can be
+// renamed into place atomically. A crash never leaves a truncated file at destination.
+async fn write_temp(fullpath: &Path, data: &mut ByteStream) -> Result<NamedTempFile> {
+    let dir = fullpath.parent().unwrap().to_path_buf();
+    let (temp, file) = blocking(move || {
+        let temp = tempfile::Builder::new()
+            .prefix(TEMP_PREFIX)
+            .tempfile_in(dir)?;
+        let file = temp.reopen()?;
+        Ok((temp, file))
+    })
+    .await?;
+    let mut dest = File::from_std(file);
+    tokio::io::copy(data, &mut dest).await?;
+    dest.sync_all().await?;
+    Ok(temp)
+}
+
+// rename the temp file into place, failing if the destination exists unless `replace`
+async fn persist(temp: NamedTempFile, fullpath: &Path, replace: bool) -> std::io::Result<()> {
+    let fullpath = fullpath.to_path_buf();
+    blocking(move || {
+        let result = if replace {
+            temp.persist(fullpath)
+        } else {
+            temp.persist_noclobber(fullpath)
+        };
+        result.map(drop).map_err(|e| e.error)
+    })
+    .await
+}
+
+// persist the rename
+async fn sync_parent(fullpath: &Path) -> Result<()> {
+    let dir = fullpath.parent().unwrap().to_path_buf();
+    blocking(move || std::fs::File::open(dir)?.sync_all()).await?;
+    Ok(())
+}
+
+fn file_meta(path: PathBuf, metadata: &Metadata) -> FileMeta {
+    let mtime = metadata.modified().ok();
+    // derived from size & mtime, so that files are never read to compute it
+    let etag = mtime
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map(|mtime| format!("{:x}-{:x}", metadata.len(), mtime.as_nanos()));
+    FileMeta {
+        path,
+        size: metadata.len(),
+        mtime: mtime.map(DateTime::from),
+        etag,
+    }
+}
+
 #[async_trait]
 impl StorageProvider for FSStorage {
     async fn get_file(&self, path: &Path) -> Result<ByteStream> {
@@ -56,18 +128,21 @@ impl StorageProvider for FSStorage {
 
         let mut src = File::open(&fullpath).await?;
         if src.metadata().await?.len() > self.memory_limit {
-            let sync_dest = NamedTempFile::new()?;
-            let mut dest = File::from_std(sync_dest.reopen()?);
-
-            tokio::io::copy(&mut src, &mut dest).await?;
-            dest.sync_all().await?;
-
-            Ok(ByteStream::try_from(sync_dest)?)
+            // the stream holds its own handle, so the file can be replaced or deleted meanwhile
+            Ok(ByteStream::from_path(&fullpath)?)
         } else {
             let mut buf = vec![];
             src.read_to_end(&mut buf).await?;
 
-            Ok(ByteStream::Memory(Cursor::new(buf)))
+            Ok(ByteStream::from(buf))
+        }
+    }
+
+    async fn stat(&self, path: &Path) -> Result<FileMeta> {
+        let fullpath = get_fullpath(&*self.base, path)?;
+        match tokio::fs::metadata(&fullpath).await {
+            Ok(metadata) if metadata.is_file() => Ok(file_meta(path.to_path_buf(), &metadata)),
+            _ => Err(StorageError::FileNotExists(path.to_path_buf())),
         }
     }
 
@@ -77,10 +152,23 @@ impl StorageProvider for FSStorage {
             return Err(StorageError::FileExists(path.to_path_buf()));
         }
 
-        let mut dest = File::create(&fullpath).await?;
-        tokio::io::copy(&mut data, &mut dest).await?;
+        let temp = write_temp(&fullpath, &mut data).await?;
+        // fails if the file is created meanwhile
+        persist(temp, &fullpath, false).await.map_err(|e| {
+            if e.kind() == ErrorKind::AlreadyExists {
+                StorageError::FileExists(path.to_path_buf())
+            } else {
+                StorageError::IOError(e)
+            }
+        })?;
+        sync_parent(&fullpath).await
+    }
 
-        Ok(())
+    async fn replace_file(&self, path: &Path, mut data: ByteStream) -> Result<()> {
+        let fullpath = get_fullpath(&*self.base, path)?;
+        let temp = write_temp(&fullpath, &mut data).await?;
+        persist(temp, &fullpath, true).await?;
+        sync_parent(&fullpath).await
     }
 
     async fn delete_file(&self, path: &Path) -> Result<()> {
@@ -93,4 +181,38 @@ impl StorageProvider for FSStorage {
 
         Ok(())
     }
+
+    async fn list(&self, prefix: &Path) -> Result<Vec<FileMeta>> {
+        // keys are matched by string prefix, so the walk starts from the deepest directory
+        // the prefix covers, e.g. `foo/` for `foo/ba`
+        let prefix = prefix.to_string_lossy();
+        let start = match prefix.rsplit_once('/') {
+            Some((dir, _)) => get_fullpath(&*self.base, Path::new(dir))?,
+            None => self.base.clone(),
+        };
+        let mut files = vec![];
+        let mut dirs = vec![start];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = tokio::fs::metadata(entry.path()).await?;
+                let path = entry.path().strip_prefix(&self.base).unwrap().to_path_buf();
+                if metadata.is_dir() {
+                    // skip directories which can't contain matching keys
+                    let dir = format!("{}/", path.to_string_lossy());
+                    if dir.starts_with(&*prefix) || prefix.starts_with(&dir) {
+                        dirs.push(entry.path());
+                    }
+                } else if metadata.is_file() && path.to_string_lossy().starts_with(&*prefix) {
+                    files.push(file_meta(path, &metadata));
+                }
+            }
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(files)
+    }
 }
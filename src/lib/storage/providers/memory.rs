@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::io::AsyncReadExt;
+
+use crate::error::StorageError;
+use crate::storage::types::*;
+
+use super::Result;
+use super::StorageProvider;
+
+#[derive(Debug, Clone)]
+struct MemoryFile {
+    data: Vec<u8>,
+    mtime: DateTime<Utc>,
+}
+
+// Keeps everything in memory, mainly for tests.
+// Behaves like `FSStorage`: existing files can only be overwritten by `replace_file`.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    files: Mutex<BTreeMap<PathBuf, MemoryFile>>,
+}
+
+// reject paths escaping the root
+fn normalize(path: &Path) -> Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => (),
+            _ => return Err(StorageError::InvalidPath(path.to_path_buf())),
+        }
+    }
+    Ok(normalized)
+}
+
+fn file_meta(path: &Path, file: &MemoryFile) -> FileMeta {
+    FileMeta {
+        path: path.to_path_buf(),
+        size: file.data.len() as u64,
+        mtime: Some(file.mtime),
+        etag: Some(format!("{:x}", md5::compute(&file.data))),
+    }
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // copy of all stored files
+    pub fn files(&self) -> BTreeMap<PathBuf, Vec<u8>> {
+        self.files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, file)| (path.clone(), file.data.clone()))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.lock().unwrap().is_empty()
+    }
+}
+
+#[async_trait]
+impl StorageProvider for MemoryStorage {
+    async fn get_file(&self, path: &Path) -> Result<ByteStream> {
+        let key = normalize(path)?;
+        self.files
+            .lock()
+            .unwrap()
+            .get(&key)
+            .map(|file| ByteStream::from(file.data.clone()))
+            .ok_or_else(|| StorageError::FileNotExists(path.to_path_buf()))
+    }
+
+    async fn stat(&self, path: &Path) -> Result<FileMeta> {
+        let key = normalize(path)?;
+        self.files
+            .lock()
+            .unwrap()
+            .get(&key)
+            .map(|file| file_meta(path, file))
+            .ok_or_else(|| StorageError::FileNotExists(path.to_path_buf()))
+    }
+
+    async fn put_file(&self, path: &Path, mut data: ByteStream) -> Result<()> {
+        let key = normalize(path)?;
+        if self.files.lock().unwrap().contains_key(&key) {
+            return Err(StorageError::FileExists(path.to_path_buf()));
+        }
+
+        let mut buf = vec![];
+        data.read_to_end(&mut buf).await?;
+
+        // the lock can't be held across await, so check again
+        let mut files = self.files.lock().unwrap();
+        if files.contains_key(&key) {
+            return Err(StorageError::FileExists(path.to_path_buf()));
+        }
+        files.insert(
+            key,
+            MemoryFile {
+                data: buf,
+                mtime: Utc::now(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn replace_file(&self, path: &Path, mut data: ByteStream) -> Result<()> {
+        let key = normalize(path)?;
+        let mut buf = vec![];
+        data.read_to_end(&mut buf).await?;
+        self.files.lock().unwrap().insert(
+            key,
+            MemoryFile {
+                data: buf,
+                mtime: Utc::now(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn delete_file(&self, path: &Path) -> Result<()> {
+        let key = normalize(path)?;
+        self.files
+            .lock()
+            .unwrap()
+            .remove(&key)
+            .map(|_| ())
+            .ok_or_else(|| StorageError::FileNotExists(path.to_path_buf()))
+    }
+
+    async fn list(&self, prefix: &Path) -> Result<Vec<FileMeta>> {
+        let prefix = prefix.to_string_lossy();
+        Ok(self
+            .files
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(path, _)| path.to_string_lossy().starts_with(&*prefix))
+            .map(|(path, file)| file_meta(path, file))
+            .collect())
+    }
+}
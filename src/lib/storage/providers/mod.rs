@@ -1,25 +1,115 @@
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use async_trait::async_trait;
 
+pub use faulty::*;
 pub use filesystem::*;
+pub use memory::*;
+pub use parse::from_url;
 pub use s3::*;
 
 use crate::error::StorageError;
 
 use super::types::*;
 
+mod faulty;
 mod filesystem;
+mod memory;
+mod parse;
 mod s3;
+pub(crate) mod web_identity;
 
 #[async_trait]
 pub trait StorageProvider: Sync + Send {
+    // large files are streamed, so the whole file is never held in memory
     async fn get_file(&self, path: &Path) -> Result<ByteStream>;
-    // async fn get_file_meta();
+    // get given byte range of a file, the range is clamped to the file size
+    async fn get_range(&self, path: &Path, range: Range<u64>) -> Result<ByteStream> {
+        Ok(self.get_file(path).await?.slice(range))
+    }
+    async fn stat(&self, path: &Path) -> Result<FileMeta>;
     async fn put_file(&self, path: &Path, data: ByteStream) -> Result<()>;
+    // put a file, overwriting the existing one
+    // NOTE
+    // It's atomic only if the provider overrides it. The default implementation deletes
+    // the file first, so it may be missing for a while.
+    async fn replace_file(&self, path: &Path, data: ByteStream) -> Result<()> {
+        match self.delete_file(path).await {
+            Ok(_) | Err(StorageError::FileNotExists(_)) => (),
+            Err(e) => return Err(e),
+        }
+        self.put_file(path, data).await
+    }
     // fn set_file_meta();
     async fn delete_file(&self, path: &Path) -> Result<()>;
-    // async fn list_files(prefix: String) -> ;
+    // list all files whose path starts with prefix (as string, like s3)
+    async fn list(&self, prefix: &Path) -> Result<Vec<FileMeta>>;
+}
+
+// share a provider, e.g. to inspect a storage used by a pool in tests
+#[async_trait]
+impl<T: StorageProvider + ?Sized> StorageProvider for Arc<T> {
+    async fn get_file(&self, path: &Path) -> Result<ByteStream> {
+        (**self).get_file(path).await
+    }
+
+    async fn get_range(&self, path: &Path, range: Range<u64>) -> Result<ByteStream> {
+        (**self).get_range(path, range).await
+    }
+
+    async fn stat(&self, path: &Path) -> Result<FileMeta> {
+        (**self).stat(path).await
+    }
+
+    async fn put_file(&self, path: &Path, data: ByteStream) -> Result<()> {
+        (**self).put_file(path, data).await
+    }
+
+    async fn replace_file(&self, path: &Path, data: ByteStream) -> Result<()> {
+        (**self).replace_file(path, data).await
+    }
+
+    async fn delete_file(&self, path: &Path) -> Result<()> {
+        (**self).delete_file(path).await
+    }
+
+    async fn list(&self, prefix: &Path) -> Result<Vec<FileMeta>> {
+        (**self).list(prefix).await
+    }
+}
+
+// providers constructed at runtime, see `from_url`
+#[async_trait]
+impl<T: StorageProvider + ?Sized> StorageProvider for Box<T> {
+    async fn get_file(&self, path: &Path) -> Result<ByteStream> {
+        (**self).get_file(path).await
+    }
+
+    async fn get_range(&self, path: &Path, range: Range<u64>) -> Result<ByteStream> {
+        (**self).get_range(path, range).await
+    }
+
+    async fn stat(&self, path: &Path) -> Result<FileMeta> {
+        (**self).stat(path).await
+    }
+
+    async fn put_file(&self, path: &Path, data: ByteStream) -> Result<()> {
+        (**self).put_file(path, data).await
+    }
+
+    async fn replace_file(&self, path: &Path, data: ByteStream) -> Result<()> {
+        (**self).replace_file(path, data).await
+    }
+
+    async fn delete_file(&self, path: &Path) -> Result<()> {
+        (**self).delete_file(path).await
+    }
+
+    async fn list(&self, prefix: &Path) -> Result<Vec<FileMeta>> {
+        (**self).list(prefix).await
+    }
 }
 
 fn get_fullpath(base: &Path, path: &Path) -> Result<PathBuf> {
@@ -0,0 +1,91 @@
+use std::str::FromStr;
+
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use crate::error::StorageError;
+
+use super::Result;
+use super::{FSStorage, MemoryStorage, S3Addressing, S3StorageBuilder, StorageProvider};
+
+fn invalid(url: &str, reason: impl AsRef<str>) -> StorageError {
+    StorageError::InvalidURL(format!("{}: {}", url, reason.as_ref()))
+}
+
+fn parse_param<T: FromStr>(url: &str, key: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| invalid(url, format!("invalid value of {}: {}", key, value)))
+}
+
+// Construct a storage provider from url.
+//
+// Supported schemes:
+// - `file:///srv/repo?memory_limit=...`
+// - `s3://bucket/prefix?endpoint=...&region=...&profile=...&part_size=...&concurrency=...&memory_limit=...&addressing=path`
+// - `memory://`
+//
+// Credentials aren't accepted in urls. S3 credentials are resolved from environment,
+// shared profile or web identity, see `S3Credential::Chain`.
+pub fn from_url(s: &str) -> Result<Box<dyn StorageProvider>> {
+    let url = Url::parse(s).map_err(|e| invalid(s, e.to_string()))?;
+    if !url.username().is_empty() || url.password().is_some() {
+        return Err(invalid(s, "credentials in url are not allowed"));
+    }
+
+    match url.scheme() {
+        "file" => {
+            let path = url
+                .to_file_path()
+                .map_err(|_| invalid(s, "invalid file path"))?;
+            let mut memory_limit = None;
+            for (key, value) in url.query_pairs() {
+                match &*key {
+                    "memory_limit" => memory_limit = Some(parse_param(s, &key, &value)?),
+                    _ => return Err(invalid(s, format!("unknown parameter: {}", key))),
+                }
+            }
+            Ok(match memory_limit {
+                Some(limit) => Box::new(FSStorage::new_with_limit(path, limit)),
+                None => Box::new(FSStorage::new(path)),
+            })
+        }
+        "s3" => {
+            let bucket = url
+                .host_str()
+                .filter(|bucket| !bucket.is_empty())
+                .ok_or_else(|| invalid(s, "missing bucket"))?;
+            // the path is percent-encoded in url, while keys are not
+            let base = percent_decode_str(url.path())
+                .decode_utf8()
+                .map_err(|_| invalid(s, "invalid prefix"))?;
+            let mut builder = S3StorageBuilder::new()
+                .with_bucket(bucket)
+                .with_base(base.trim_start_matches('/'));
+            for (key, value) in url.query_pairs() {
+                builder = match &*key {
+                    "endpoint" => builder.with_endpoint(&value),
+                    "region" => builder.with_region(&value),
+                    "profile" => builder.with_profile(&value),
+                    "part_size" => builder.with_part_size(parse_param(s, &key, &value)?),
+                    "concurrency" => builder.with_concurrency(parse_param(s, &key, &value)?),
+                    "memory_limit" => builder.with_memory_limit(parse_param(s, &key, &value)?),
+                    "addressing" => builder.with_addressing(match &*value {
+                        "path" => S3Addressing::Path,
+                        "virtual-hosted" => S3Addressing::VirtualHosted,
+                        _ => return Err(invalid(s, format!("invalid addressing: {}", value))),
+                    }),
+                    _ => return Err(invalid(s, format!("unknown parameter: {}", key))),
+                };
+            }
+            Ok(Box::new(builder.build()?))
+        }
+        "memory" => {
+            if url.query().is_some() {
+                return Err(invalid(s, "memory storage takes no parameters"));
+            }
+            Ok(Box::new(MemoryStorage::new()))
+        }
+        scheme => Err(invalid(s, format!("unsupported scheme: {}", scheme))),
+    }
+}
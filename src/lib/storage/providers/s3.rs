@@ -1,22 +1,38 @@
-use std::convert::TryFrom;
-use std::io::{Cursor, SeekFrom};
+use std::collections::HashMap;
+use std::env;
+use std::fmt::{Debug, Formatter};
+use std::io::{ErrorKind, SeekFrom};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 
 use async_trait::async_trait;
-use rusoto_core::credential::StaticProvider;
+use bytes::Bytes;
+use chrono::{DateTime, Duration, Utc};
+use futures::stream::{FuturesUnordered, StreamExt};
+use rusoto_core::credential::{
+    AutoRefreshingProvider, ChainProvider, CredentialsError, EnvironmentProvider, ProfileProvider,
+    StaticProvider,
+};
 use rusoto_core::{Client, Region, RusotoError};
 use rusoto_s3::{
-    DeleteObjectRequest, GetObjectError, GetObjectRequest, PutObjectRequest, S3Client,
-    StreamingBody, S3,
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CreateMultipartUploadRequest, DeleteObjectRequest, GetObjectError,
+    GetObjectRequest, HeadObjectError, HeadObjectRequest, ListMultipartUploadsRequest,
+    ListObjectsV2Request, ListPartsRequest, PutObjectRequest, S3Client, StreamingBody,
+    UploadPartRequest, S3,
 };
-use tempfile::NamedTempFile;
-use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
 
-use crate::consts::STORAGE_MEMORY_LIMIT;
+use crate::consts::{
+    S3_MAX_PARTS, S3_MIN_PART_SIZE, S3_PART_SIZE, S3_UPLOAD_CONCURRENCY, S3_UPLOAD_EXPIRY,
+    STORAGE_MEMORY_LIMIT,
+};
 use crate::error::{S3Error, StorageError};
+use crate::storage::providers::web_identity::WebIdentityProvider;
 use crate::storage::providers::{get_fullpath, StorageProvider};
-use crate::storage::types::ByteStream;
+use crate::storage::types::{ByteStream, FileMeta, RangeSource};
 
 use super::Result;
 
@@ -25,16 +41,63 @@ pub struct S3Storage {
     bucket: String,
     base: PathBuf,
     memory_limit: u64,
+    part_size: u64,     // objects larger than this are uploaded in parts
+    concurrency: usize, // max parts uploaded in parallel
+}
+
+// Where to get credentials from. Prefer anything other than `Static` so that
+// secrets needn't be kept in config files.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum S3Credential {
+    Static {
+        key: String,
+        secret: String,
+        token: Option<String>,
+    },
+    // AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN
+    Environment,
+    // shared credentials file, `None` for default profile and file location
+    Profile {
+        name: Option<String>,
+        file: Option<PathBuf>,
+    },
+    // AWS_WEB_IDENTITY_TOKEN_FILE, AWS_ROLE_ARN and AWS_ROLE_SESSION_NAME
+    WebIdentity,
+    // web identity if configured, then environment, profile, ecs and instance metadata
+    Chain,
+}
+
+impl Default for S3Credential {
+    fn default() -> Self {
+        Self::Chain
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum S3Addressing {
+    // https://endpoint/bucket/key
+    Path,
+    // https://bucket.endpoint/key
+    VirtualHosted,
+}
+
+impl Default for S3Addressing {
+    fn default() -> Self {
+        Self::Path
+    }
 }
 
 #[derive(Clone, Eq, PartialEq, Default, Hash)]
 pub struct S3StorageBuilder {
-    name: Option<String>,
+    region: Option<String>,
     endpoint: Option<String>,
-    credential: Option<(String, String)>,
+    credential: S3Credential,
+    addressing: S3Addressing,
     bucket: Option<String>,
     base: Option<PathBuf>,
     memory_limit: Option<u64>,
+    part_size: Option<u64>,
+    concurrency: Option<usize>,
 }
 
 impl S3StorageBuilder {
@@ -42,9 +105,10 @@ impl S3StorageBuilder {
         Default::default()
     }
 
-    pub fn with_name(self, name: impl ToString) -> Self {
+    // standard aws region, or name of the custom region if an endpoint is given
+    pub fn with_region(self, region: impl ToString) -> Self {
         Self {
-            name: Some(name.to_string()),
+            region: Some(region.to_string()),
             ..self
         }
     }
@@ -57,10 +121,39 @@ impl S3StorageBuilder {
     }
 
     pub fn with_credential(self, key: impl ToString, secret: impl ToString) -> Self {
-        Self {
-            credential: Some((key.to_string(), secret.to_string())),
-            ..self
-        }
+        self.with_credential_source(S3Credential::Static {
+            key: key.to_string(),
+            secret: secret.to_string(),
+            token: None,
+        })
+    }
+
+    pub fn with_session_credential(
+        self,
+        key: impl ToString,
+        secret: impl ToString,
+        token: impl ToString,
+    ) -> Self {
+        self.with_credential_source(S3Credential::Static {
+            key: key.to_string(),
+            secret: secret.to_string(),
+            token: Some(token.to_string()),
+        })
+    }
+
+    pub fn with_profile(self, name: impl ToString) -> Self {
+        self.with_credential_source(S3Credential::Profile {
+            name: Some(name.to_string()),
+            file: None,
+        })
+    }
+
+    pub fn with_credential_source(self, credential: S3Credential) -> Self {
+        Self { credential, ..self }
+    }
+
+    pub fn with_addressing(self, addressing: S3Addressing) -> Self {
+        Self { addressing, ..self }
     }
 
     pub fn with_bucket(self, bucket: impl ToString) -> Self {
@@ -84,8 +177,36 @@ impl S3StorageBuilder {
         }
     }
 
+    pub fn with_part_size(self, part_size: u64) -> Self {
+        Self {
+            part_size: Some(part_size),
+            ..self
+        }
+    }
+
+    pub fn with_concurrency(self, concurrency: usize) -> Self {
+        Self {
+            concurrency: Some(concurrency),
+            ..self
+        }
+    }
+
+    fn part_size(&self) -> Result<u64> {
+        let part_size = self.part_size.unwrap_or(S3_PART_SIZE);
+        if part_size < S3_MIN_PART_SIZE {
+            return Err(S3Error::BuilderError(format!(
+                "part size must be at least {} bytes",
+                S3_MIN_PART_SIZE
+            ))
+            .into());
+        }
+        Ok(part_size)
+    }
+
     pub fn build_with_client(self, client: S3Client) -> Result<S3Storage> {
         Ok(S3Storage {
+            part_size: self.part_size()?,
+            concurrency: self.concurrency.unwrap_or(S3_UPLOAD_CONCURRENCY).max(1),
             client,
             bucket: self
                 .bucket
@@ -95,34 +216,85 @@ impl S3StorageBuilder {
         })
     }
 
-    pub fn build(self) -> Result<S3Storage> {
-        let (key, secret) = self
-            .credential
-            .ok_or_else(|| S3Error::BuilderError(String::from("missing credential field")))?;
-        let name = self
-            .name
-            .ok_or_else(|| S3Error::BuilderError(String::from("missing name field")))?;
-        let endpoint = self
-            .endpoint
-            .ok_or_else(|| S3Error::BuilderError(String::from("missing endpoint field")))?;
-        let bucket = self
-            .bucket
-            .ok_or_else(|| S3Error::BuilderError(String::from("missing bucket field")))?;
-
-        let credential = StaticProvider::new_minimal(key, secret);
-        let http_client = rusoto_core::HttpClient::new().unwrap();
-        let common_client = Client::new_with(credential, http_client);
-
-        let region = Region::Custom { name, endpoint };
-        let s3_client = S3Client::new_with_client(common_client, region);
+    fn region(&self) -> Result<Region> {
+        Ok(match (&self.region, &self.endpoint) {
+            (region, Some(endpoint)) => Region::Custom {
+                name: region
+                    .clone()
+                    .unwrap_or_else(|| Region::default().name().to_string()),
+                endpoint: endpoint.clone(),
+            },
+            (Some(region), None) => Region::from_str(region)
+                .map_err(|e| S3Error::BuilderError(format!("invalid region: {}", e)))?,
+            // AWS_DEFAULT_REGION or AWS_REGION, fallback to us-east-1
+            (None, None) => Region::default(),
+        })
+    }
 
-        Ok(S3Storage {
-            client: s3_client,
-            bucket,
-            base: self.base.unwrap_or_default(),
-            memory_limit: self.memory_limit.unwrap_or(STORAGE_MEMORY_LIMIT),
+    fn client(&self, region: &Region) -> Result<Client> {
+        let http_client = rusoto_core::HttpClient::new()
+            .map_err(|e| S3Error::BuilderError(format!("unable to create http client: {}", e)))?;
+        let map_err = |e: CredentialsError| {
+            S3Error::BuilderError(format!("unable to load credentials: {}", e.message))
+        };
+
+        Ok(match &self.credential {
+            S3Credential::Static { key, secret, token } => Client::new_with(
+                StaticProvider::new(key.clone(), secret.clone(), token.clone(), None),
+                http_client,
+            ),
+            S3Credential::Environment => {
+                Client::new_with(EnvironmentProvider::default(), http_client)
+            }
+            S3Credential::Profile { name, file } => {
+                let mut provider = match file {
+                    Some(file) => ProfileProvider::with_default_configuration(file),
+                    None => ProfileProvider::new().map_err(map_err)?,
+                };
+                if let Some(name) = name {
+                    provider.set_profile(name.as_str());
+                }
+                Client::new_with(provider, http_client)
+            }
+            S3Credential::WebIdentity => Client::new_with(
+                AutoRefreshingProvider::new(
+                    WebIdentityProvider::from_env(region).map_err(map_err)?,
+                )
+                .map_err(map_err)?,
+                http_client,
+            ),
+            S3Credential::Chain => {
+                if env::var_os("AWS_WEB_IDENTITY_TOKEN_FILE").is_some() {
+                    Client::new_with(
+                        AutoRefreshingProvider::new(
+                            WebIdentityProvider::from_env(region).map_err(map_err)?,
+                        )
+                        .map_err(map_err)?,
+                        http_client,
+                    )
+                } else {
+                    Client::new_with(ChainProvider::new(), http_client)
+                }
+            }
         })
     }
+
+    // NOTE
+    // Virtual-hosted addressing is rejected for now. rusoto always puts the bucket into the
+    // request path, and rewriting the host after the request is signed breaks the signature.
+    // Path style works with all s3 compatible services, and with existing aws buckets.
+    pub fn build(self) -> Result<S3Storage> {
+        if self.addressing == S3Addressing::VirtualHosted {
+            return Err(S3Error::BuilderError(String::from(
+                "virtual-hosted style addressing is not supported yet, use path style",
+            ))
+            .into());
+        }
+
+        let region = self.region()?;
+        let client = self.client(&region)?;
+        self.build_with_client(S3Client::new_with_client(client, region))
+    }
 }
 
 fn map_get_err(e: RusotoError<GetObjectError>) -> StorageError {
@@ -135,52 +307,391 @@ fn map_get_err(e: RusotoError<GetObjectError>) -> StorageError {
     }
 }
 
-async fn guess_mime(stream: &mut ByteStream) -> Option<&str> {
+fn map_head_err(e: RusotoError<HeadObjectError>, path: &Path) -> StorageError {
+    match e {
+        RusotoError::Service(HeadObjectError::NoSuchKey(_)) => {
+            StorageError::FileNotExists(path.to_path_buf())
+        }
+        // HEAD responses have no body, so missing keys are reported as bare 404
+        RusotoError::Unknown(resp) if resp.status.as_u16() == 404 => {
+            StorageError::FileNotExists(path.to_path_buf())
+        }
+        _ => StorageError::S3Error(e.into()),
+    }
+}
+
+// head responses use http date, while list responses use iso 8601
+fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(time)
+        .or_else(|_| DateTime::parse_from_rfc3339(time))
+        .ok()
+        .map(DateTime::from)
+}
+
+fn parse_etag(etag: String) -> String {
+    etag.trim_matches('"').to_string()
+}
+
+async fn guess_mime(stream: &mut ByteStream) -> Result<Option<&'static str>> {
     let mut buf = [0; 512];
-    let bytes = stream.read(&mut buf).await.unwrap();
-    stream
-        .seek(SeekFrom::Current(-(bytes as i64)))
-        .await
-        .unwrap();
-    infer::get(&buf).map(|mime| mime.mime_type())
+    let bytes = stream.read(&mut buf).await?;
+    stream.seek(SeekFrom::Current(-(bytes as i64))).await?;
+    Ok(infer::get(&buf[..bytes]).map(|mime| mime.mime_type()))
+}
+
+// An object fetched by range on demand.
+// Ranges are requested with `If-Match`, so reading fails if the object is replaced meanwhile,
+// instead of mixing old and new content.
+struct S3Object {
+    client: S3Client,
+    bucket: String,
+    key: String,
+    etag: Option<String>,
+}
+
+impl Debug for S3Object {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Object")
+            .field("bucket", &self.bucket)
+            .field("key", &self.key)
+            .field("etag", &self.etag)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl RangeSource for S3Object {
+    async fn get_range(&self, range: Range<u64>) -> std::io::Result<Bytes> {
+        if range.is_empty() {
+            return Ok(Bytes::new());
+        }
+        let req = GetObjectRequest {
+            bucket: self.bucket.clone(),
+            key: self.key.clone(),
+            range: Some(format!("bytes={}-{}", range.start, range.end - 1)),
+            if_match: self.etag.clone(),
+            ..Default::default()
+        };
+        let resp = self
+            .client
+            .get_object(req)
+            .await
+            .map_err(|e| std::io::Error::new(ErrorKind::Other, map_get_err(e)))?;
+        let mut buf = Vec::with_capacity((range.end - range.start) as usize);
+        resp.body
+            .unwrap()
+            .into_async_read()
+            .read_to_end(&mut buf)
+            .await?;
+        Ok(Bytes::from(buf))
+    }
+}
+
+impl S3Storage {
+    // stream of an object fetched `part_size` at a time
+    fn object_stream(&self, key: String, size: u64, etag: Option<String>) -> ByteStream {
+        let source = S3Object {
+            client: self.client.clone(),
+            bucket: self.bucket.clone(),
+            key,
+            etag,
+        };
+        ByteStream::from_source(Arc::new(source), size).with_chunk_size(self.part_size as usize)
+    }
+
+    // size and raw etag of an object
+    async fn head(&self, key: &str, path: &Path) -> Result<(u64, Option<String>)> {
+        let req = HeadObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            ..Default::default()
+        };
+        let resp = self
+            .client
+            .head_object(req)
+            .await
+            .map_err(|e| map_head_err(e, path))?;
+        Ok((resp.content_length.unwrap_or_default() as u64, resp.e_tag))
+    }
+
+    // Find an unfinished upload of the key to resume.
+    // Uploads older than `S3_UPLOAD_EXPIRY` are aborted, so that their parts don't pile up.
+    pub(crate) async fn find_upload(&self, key: &str) -> Result<Option<String>> {
+        let expire = Utc::now() - Duration::seconds(S3_UPLOAD_EXPIRY);
+        let mut latest = None;
+        let mut key_marker = None;
+        let mut upload_id_marker = None;
+        loop {
+            let req = ListMultipartUploadsRequest {
+                bucket: self.bucket.clone(),
+                prefix: Some(key.to_string()),
+                key_marker,
+                upload_id_marker,
+                ..Default::default()
+            };
+            let resp = self
+                .client
+                .list_multipart_uploads(req)
+                .await
+                .map_err(|e| StorageError::S3Error(e.into()))?;
+
+            for upload in resp.uploads.unwrap_or_default() {
+                let upload_id = match upload.upload_id {
+                    Some(upload_id) if upload.key.as_deref() == Some(key) => upload_id,
+                    _ => continue,
+                };
+                let initiated = upload.initiated.as_deref().and_then(parse_time);
+                if initiated.map_or(false, |initiated| initiated < expire) {
+                    self.abort_upload(key, &upload_id).await?;
+                } else if latest
+                    .as_ref()
+                    .map_or(true, |(latest, _)| initiated > *latest)
+                {
+                    latest = Some((initiated, upload_id));
+                }
+            }
+
+            key_marker = resp.next_key_marker;
+            upload_id_marker = resp.next_upload_id_marker;
+            if !resp.is_truncated.unwrap_or(false) || key_marker.is_none() {
+                break;
+            }
+        }
+        Ok(latest.map(|(_, upload_id)| upload_id))
+    }
+
+    async fn abort_upload(&self, key: &str, upload_id: &str) -> Result<()> {
+        let req = AbortMultipartUploadRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            upload_id: upload_id.to_string(),
+            ..Default::default()
+        };
+        self.client
+            .abort_multipart_upload(req)
+            .await
+            .map_err(|e| StorageError::S3Error(e.into()))?;
+        Ok(())
+    }
+
+    pub(crate) async fn create_upload(
+        &self,
+        key: &str,
+        content_type: Option<String>,
+    ) -> Result<String> {
+        let req = CreateMultipartUploadRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            content_type,
+            ..Default::default()
+        };
+        let resp = self
+            .client
+            .create_multipart_upload(req)
+            .await
+            .map_err(|e| StorageError::S3Error(e.into()))?;
+        resp.upload_id
+            .ok_or_else(|| S3Error::ResponseError(String::from("missing upload id")).into())
+    }
+
+    // part number -> etag of uploaded parts
+    async fn list_parts(&self, key: &str, upload_id: &str) -> Result<HashMap<i64, String>> {
+        let mut parts = HashMap::new();
+        let mut marker = None;
+        loop {
+            let req = ListPartsRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_string(),
+                upload_id: upload_id.to_string(),
+                part_number_marker: marker,
+                ..Default::default()
+            };
+            let resp = self
+                .client
+                .list_parts(req)
+                .await
+                .map_err(|e| StorageError::S3Error(e.into()))?;
+            parts.extend(
+                resp.parts
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|part| Some((part.part_number?, parse_etag(part.e_tag?)))),
+            );
+            marker = resp.next_part_number_marker;
+            if !resp.is_truncated.unwrap_or(false) || marker.is_none() {
+                break;
+            }
+        }
+        Ok(parts)
+    }
+
+    pub(crate) async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i64,
+        data: ByteStream,
+    ) -> Result<CompletedPart> {
+        let req = UploadPartRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            upload_id: upload_id.to_string(),
+            part_number,
+            content_length: Some(data.size() as i64),
+            body: Some(StreamingBody::new(data)),
+            ..Default::default()
+        };
+        let resp = self
+            .client
+            .upload_part(req)
+            .await
+            .map_err(|e| StorageError::S3Error(e.into()))?;
+        Ok(CompletedPart {
+            e_tag: resp.e_tag,
+            part_number: Some(part_number),
+        })
+    }
+
+    // Upload in parts, parts already uploaded by an interrupted upload are skipped.
+    // Unfinished uploads aren't aborted on failure so that they can be resumed.
+    async fn put_multipart(
+        &self,
+        key: String,
+        data: ByteStream,
+        content_type: Option<String>,
+    ) -> Result<()> {
+        // s3 accepts at most 10000 parts
+        let part_size = self
+            .part_size
+            .max((data.size() + S3_MAX_PARTS - 1) / S3_MAX_PARTS);
+
+        let (upload_id, uploaded) = if let Some(upload_id) = self.find_upload(&key).await? {
+            let uploaded = self.list_parts(&key, &upload_id).await?;
+            (upload_id, uploaded)
+        } else {
+            (
+                self.create_upload(&key, content_type).await?,
+                HashMap::new(),
+            )
+        };
+
+        let mut parts = vec![];
+        let mut pending = FuturesUnordered::new();
+        for (idx, offset) in (0..data.size()).step_by(part_size as usize).enumerate() {
+            let part_number = idx as i64 + 1;
+            // parts are sub-streams sharing the data, nothing is copied into memory
+            let part = data.slice(offset..offset + part_size);
+
+            // etag of a part is its md5, only parts found in the resumed upload are hashed
+            if let Some(etag) = uploaded.get(&part_number) {
+                let mut context = md5::Context::new();
+                let mut chunks = part.clone();
+                while let Some(chunk) = chunks.next().await {
+                    context.consume(chunk?);
+                }
+                if &format!("{:x}", context.compute()) == etag {
+                    parts.push(CompletedPart {
+                        e_tag: Some(etag.clone()),
+                        part_number: Some(part_number),
+                    });
+                    continue;
+                }
+            }
+
+            if pending.len() >= self.concurrency {
+                parts.push(pending.next().await.unwrap()?);
+            }
+            pending.push(self.upload_part(&key, &upload_id, part_number, part));
+        }
+        while let Some(part) = pending.next().await {
+            parts.push(part?);
+        }
+        parts.sort_by_key(|part| part.part_number);
+
+        let req = CompleteMultipartUploadRequest {
+            bucket: self.bucket.clone(),
+            key,
+            upload_id,
+            multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+            ..Default::default()
+        };
+        self.client
+            .complete_multipart_upload(req)
+            .await
+            .map_err(|e| StorageError::S3Error(e.into()))?;
+        Ok(())
+    }
 }
 
 #[async_trait]
 impl StorageProvider for S3Storage {
     async fn get_file(&self, path: &Path) -> Result<ByteStream> {
         let fullpath = get_fullpath(&self.base, path)?;
+        let key = fullpath.to_str().unwrap().to_string();
+
+        let (size, etag) = self.head(&key, path).await?;
+        if size > self.memory_limit {
+            // large objects are fetched by ranges when the stream is read
+            return Ok(self.object_stream(key, size, etag));
+        }
 
         let req = GetObjectRequest {
             bucket: self.bucket.clone(),
-            key: fullpath.to_str().unwrap().to_string(),
+            key,
+            if_match: etag,
             ..Default::default()
         };
         let data = self.client.get_object(req).await.map_err(map_get_err)?;
-        let mut src = data.body.unwrap().into_async_read();
+        let mut buf = vec![];
+        data.body
+            .unwrap()
+            .into_async_read()
+            .read_to_end(&mut buf)
+            .await?;
+        Ok(ByteStream::from(buf))
+    }
 
-        if data
-            .content_length
-            .map_or(false, |l| l > self.memory_limit as i64)
-        {
-            let sync_dest = NamedTempFile::new()?;
-            let mut dest = File::from_std(sync_dest.reopen()?);
+    // nothing is fetched until the stream is read
+    async fn get_range(&self, path: &Path, range: Range<u64>) -> Result<ByteStream> {
+        let fullpath = get_fullpath(&self.base, path)?;
+        let key = fullpath.to_str().unwrap().to_string();
 
-            tokio::io::copy(&mut src, &mut dest).await?;
-            dest.sync_all().await?;
+        let (size, etag) = self.head(&key, path).await?;
+        Ok(self.object_stream(key, size, etag).slice(range))
+    }
 
-            Ok(ByteStream::try_from(sync_dest)?)
-        } else {
-            let mut buf = vec![];
-            src.read_to_end(&mut buf).await?;
+    async fn stat(&self, path: &Path) -> Result<FileMeta> {
+        let fullpath = get_fullpath(&self.base, path)?;
 
-            Ok(ByteStream::Memory(Cursor::new(buf)))
-        }
+        let req = HeadObjectRequest {
+            bucket: self.bucket.clone(),
+            key: fullpath.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let resp = self
+            .client
+            .head_object(req)
+            .await
+            .map_err(|e| map_head_err(e, path))?;
+
+        Ok(FileMeta {
+            path: path.to_path_buf(),
+            size: resp.content_length.unwrap_or_default() as u64,
+            mtime: resp.last_modified.as_deref().and_then(parse_time),
+            etag: resp.e_tag.map(parse_etag),
+        })
     }
 
     async fn put_file(&self, path: &Path, mut data: ByteStream) -> Result<()> {
         let fullpath = get_fullpath(&self.base, path)?;
         let content_length = data.size();
-        let content_type = guess_mime(&mut data).await.map(ToString::to_string);
+        let content_type = guess_mime(&mut data).await?.map(ToString::to_string);
+
+        if content_length > self.part_size {
+            return self
+                .put_multipart(fullpath.to_str().unwrap().to_string(), data, content_type)
+                .await;
+        }
 
         let req = PutObjectRequest {
             body: Some(StreamingBody::new(data)),
@@ -199,6 +710,11 @@ impl StorageProvider for S3Storage {
         Ok(())
     }
 
+    // objects are replaced atomically by s3
+    async fn replace_file(&self, path: &Path, data: ByteStream) -> Result<()> {
+        self.put_file(path, data).await
+    }
+
     async fn delete_file(&self, path: &Path) -> Result<()> {
         let fullpath = get_fullpath(&self.base, path)?;
 
@@ -215,4 +731,42 @@ impl StorageProvider for S3Storage {
 
         Ok(())
     }
+
+    async fn list(&self, prefix: &Path) -> Result<Vec<FileMeta>> {
+        let fullprefix = get_fullpath(&self.base, prefix)?;
+
+        let mut files = vec![];
+        let mut continuation_token = None;
+        loop {
+            let req = ListObjectsV2Request {
+                bucket: self.bucket.clone(),
+                prefix: Some(fullprefix.to_str().unwrap().to_string()),
+                continuation_token,
+                ..Default::default()
+            };
+            let resp = self
+                .client
+                .list_objects_v2(req)
+                .await
+                .map_err(|e| StorageError::S3Error(e.into()))?;
+
+            for object in resp.contents.unwrap_or_default() {
+                let key = PathBuf::from(object.key.unwrap_or_default());
+                if let Ok(path) = key.strip_prefix(&self.base) {
+                    files.push(FileMeta {
+                        path: path.to_path_buf(),
+                        size: object.size.unwrap_or_default() as u64,
+                        mtime: object.last_modified.as_deref().and_then(parse_time),
+                        etag: object.e_tag.map(parse_etag),
+                    });
+                }
+            }
+
+            continuation_token = resp.next_continuation_token;
+            if !resp.is_truncated.unwrap_or(false) || continuation_token.is_none() {
+                break;
+            }
+        }
+        Ok(files)
+    }
 }
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rusoto_core::credential::{AwsCredentials, CredentialsError, ProvideAwsCredentials};
+use rusoto_core::Region;
+use xml::reader::{EventReader, XmlEvent};
+
+use crate::utils::unix_timestamp;
+
+const STS_ENDPOINT: &str = "https://sts.amazonaws.com/";
+
+// sts endpoint of the region, custom regions (s3 compatible services) use the global one
+pub(crate) fn sts_endpoint(region: &Region) -> String {
+    match region {
+        Region::Custom { .. } => String::from(STS_ENDPOINT),
+        Region::CnNorth1 | Region::CnNorthwest1 => {
+            format!("https://sts.{}.amazonaws.com.cn/", region.name())
+        }
+        _ => format!("https://sts.{}.amazonaws.com/", region.name()),
+    }
+}
+
+// Exchange a web identity token (e.g. kubernetes service account token) for temporary
+// credentials by AssumeRoleWithWebIdentity. The request needs no signature.
+#[derive(Debug, Clone)]
+pub(crate) struct WebIdentityProvider {
+    token_file: PathBuf,
+    role_arn: String,
+    session_name: String,
+    endpoint: String,
+    client: reqwest::Client,
+}
+
+impl WebIdentityProvider {
+    // AWS_WEB_IDENTITY_TOKEN_FILE, AWS_ROLE_ARN and optional AWS_ROLE_SESSION_NAME
+    pub fn from_env(region: &Region) -> Result<Self, CredentialsError> {
+        let var = |name: &str| {
+            env::var(name).map_err(|_| CredentialsError::new(format!("{} is not set", name)))
+        };
+        Ok(Self {
+            token_file: PathBuf::from(var("AWS_WEB_IDENTITY_TOKEN_FILE")?),
+            role_arn: var("AWS_ROLE_ARN")?,
+            session_name: var("AWS_ROLE_SESSION_NAME")
+                .unwrap_or_else(|_| format!("archer-{}", unix_timestamp())),
+            endpoint: sts_endpoint(region),
+            client: reqwest::Client::new(),
+        })
+    }
+}
+
+// parse credentials out of an AssumeRoleWithWebIdentity response
+pub(crate) fn parse_credentials(body: &str) -> Result<AwsCredentials, CredentialsError> {
+    let mut fields = HashMap::new();
+    let mut current = None;
+    for event in EventReader::from_str(body) {
+        match event.map_err(|e| CredentialsError::new(format!("invalid response: {}", e)))? {
+            XmlEvent::StartElement { name, .. } => current = Some(name.local_name),
+            XmlEvent::Characters(text) => {
+                if let Some(name) = current.take() {
+                    fields.insert(name, text);
+                }
+            }
+            XmlEvent::EndElement { .. } => current = None,
+            _ => (),
+        }
+    }
+
+    let field = |name: &str| {
+        fields
+            .get(name)
+            .cloned()
+            .ok_or_else(|| CredentialsError::new(format!("missing {} in response", name)))
+    };
+    let expires = DateTime::parse_from_rfc3339(&field("Expiration")?)
+        .map_err(|e| CredentialsError::new(format!("invalid expiration: {}", e)))?;
+    Ok(AwsCredentials::new(
+        field("AccessKeyId")?,
+        field("SecretAccessKey")?,
+        Some(field("SessionToken")?),
+        Some(DateTime::<Utc>::from(expires)),
+    ))
+}
+
+#[async_trait]
+impl ProvideAwsCredentials for WebIdentityProvider {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        // the token is rotated on disk, so it's read on each refresh
+        let token = tokio::fs::read_to_string(&self.token_file)
+            .await
+            .map_err(|e| CredentialsError::new(format!("unable to read token file: {}", e)))?;
+        // the token is sent in the body, so that it never shows up in access logs
+        let resp = self
+            .client
+            .post(&self.endpoint)
+            .form(&[
+                ("Action", "AssumeRoleWithWebIdentity"),
+                ("Version", "2011-06-15"),
+                ("RoleArn", &self.role_arn),
+                ("RoleSessionName", &self.session_name),
+                ("WebIdentityToken", token.trim()),
+            ])
+            .send()
+            .await
+            .map_err(|e| CredentialsError::new(format!("sts request failed: {}", e)))?;
+        let status = resp.status();
+        let body = resp
+            .text()
+            .await
+            .map_err(|e| CredentialsError::new(format!("sts request failed: {}", e)))?;
+        if !status.is_success() {
+            return Err(CredentialsError::new(format!(
+                "sts responded with {}: {}",
+                status, body
+            )));
+        }
+        parse_credentials(&body)
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::env;
 use std::io::{Seek, SeekFrom, Write};
@@ -7,15 +7,28 @@ use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use async_trait::async_trait;
+use futures::TryStreamExt;
 use itertools::Itertools;
 use rand::prelude::*;
 use rstest::rstest;
+use rusoto_core::Region;
 use tempfile::{tempdir, tempfile, NamedTempFile};
 use testcontainers::images::generic::{GenericImage, WaitFor};
 use testcontainers::{clients, Docker, RunArgs};
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use url::Url;
 
-use crate::storage::providers::{FSStorage, S3StorageBuilder};
+use crate::consts::LOCK_FILE_VERSION;
+use crate::database::{
+    ArchiveOptions, BuildTarget, Compression, DBBuilder, DBEditor, DBLink, PacmanDB,
+};
+use crate::storage::providers::web_identity::{parse_credentials, sts_endpoint};
+use crate::storage::providers::{
+    from_url, FSStorage, Fault, FaultRule, FaultyStorage, MemoryStorage, Operation, S3Addressing,
+    S3Storage, S3StorageBuilder,
+};
+use crate::storage::verify::{verify_repo, VerifyIssue};
+use crate::storage::{mirror, Lease, PackagePool};
 use crate::tests::*;
 
 use super::transaction::*;
@@ -75,6 +88,7 @@ async fn test_bytestream_read(#[case] mut stream: ByteStream) {
 
 #[rstest]
 #[case(setup_memory_bytestream())]
+#[case(setup_unnamedfile_bytestream())]
 #[case(setup_tempfile_bytestream())]
 #[case(setup_pathfile_bytestream())]
 #[tokio::test]
@@ -95,6 +109,55 @@ async fn test_bytestream_clone(#[case] mut stream: ByteStream) {
     assert_eq!(read_buf, [1, 2, 3, 4, 5], "content mismatch");
 }
 
+#[rstest]
+#[case(setup_memory_bytestream())]
+#[case(setup_unnamedfile_bytestream())]
+#[case(setup_tempfile_bytestream())]
+#[case(setup_pathfile_bytestream())]
+#[tokio::test]
+async fn test_bytestream_chunks(#[case] stream: ByteStream) {
+    let chunks: Vec<Vec<u8>> = stream
+        .with_chunk_size(2)
+        .map_ok(|chunk| chunk.to_vec())
+        .try_collect()
+        .await
+        .expect("read failed");
+    // the stream must end after the last chunk
+    assert_eq!(
+        chunks,
+        vec![vec![1, 2], vec![3, 4], vec![5]],
+        "chunks mismatch"
+    );
+}
+
+#[rstest]
+#[case(setup_memory_bytestream())]
+#[case(setup_unnamedfile_bytestream())]
+#[case(setup_tempfile_bytestream())]
+#[case(setup_pathfile_bytestream())]
+#[tokio::test]
+async fn test_bytestream_slice(#[case] stream: ByteStream) {
+    let mut slice = stream.slice(1..4);
+    assert_eq!(slice.size(), 3, "size mismatch");
+    let mut read_buf = vec![];
+    slice.read_to_end(&mut read_buf).await.expect("read failed");
+    assert_eq!(read_buf, [2, 3, 4], "content mismatch");
+
+    slice.seek(SeekFrom::End(-1)).await.expect("seek failed");
+    let mut read_buf = vec![];
+    slice.read_to_end(&mut read_buf).await.expect("read failed");
+    assert_eq!(read_buf, [4], "content mismatch");
+
+    // nested slices are clamped to the parent
+    let mut nested = slice.slice(2..10);
+    let mut read_buf = vec![];
+    nested
+        .read_to_end(&mut read_buf)
+        .await
+        .expect("read failed");
+    assert_eq!(read_buf, [4], "content mismatch");
+}
+
 #[rstest]
 #[case(setup_memory_bytestream(), PathBuf::from("tests/persist.test.1"))] // in-memory stream
 #[case(setup_unnamedfile_bytestream(), PathBuf::from("tests/persist.test.2"))] // bare file stream
@@ -113,7 +176,23 @@ async fn test_bytestream_persist(#[case] stream: ByteStream, #[case] persist_pat
     std::fs::remove_file(persist_path).expect("cleanup failed");
 }
 
-async fn must_provider_work(storage: impl StorageProvider, strict: bool) {
+#[tokio::test]
+async fn must_start_file_bytestream_at_position() {
+    let mut file = tempfile().expect("unable to create temp file");
+    assert_eq!(file.write(&[1, 2, 3, 4, 5]).expect("write failed"), 5);
+    file.seek(SeekFrom::Start(2)).expect("unable to seek");
+    let mut stream = ByteStream::try_from(file).unwrap();
+    assert_eq!(stream.size(), 3, "size mismatch");
+    let mut read_buf = vec![];
+    stream
+        .read_to_end(&mut read_buf)
+        .await
+        .expect("read failed");
+    assert_eq!(read_buf, [3, 4, 5], "content mismatch");
+}
+
+// `spill`: whether files over memory limit (5 bytes) are kept out of memory
+async fn must_provider_work(storage: impl StorageProvider, strict: bool, spill: bool) {
     storage
         .put_file("test-1".as_ref(), vec![1, 2, 3, 4, 5].into())
         .await
@@ -122,6 +201,52 @@ async fn must_provider_work(storage: impl StorageProvider, strict: bool) {
         .put_file("test-2".as_ref(), vec![1, 2, 3, 4, 5, 6].into())
         .await
         .expect("put failed");
+    storage
+        .put_file("other".as_ref(), vec![1].into())
+        .await
+        .expect("put failed");
+
+    let meta = storage.stat("test-2".as_ref()).await.expect("stat failed");
+    assert_eq!(meta.path, PathBuf::from("test-2"), "path mismatch");
+    assert_eq!(meta.size, 6, "size mismatch");
+    assert!(meta.mtime.is_some(), "missing mtime");
+    assert!(
+        matches!(
+            storage.stat("invalid-file".as_ref()).await.unwrap_err(),
+            StorageError::FileNotExists(_)
+        ),
+        "stat invalid file"
+    );
+
+    let files = storage.list("test-".as_ref()).await.expect("list failed");
+    assert_eq!(
+        files
+            .iter()
+            .map(|meta| (meta.path.clone(), meta.size))
+            .sorted()
+            .collect_vec(),
+        vec![(PathBuf::from("test-1"), 5), (PathBuf::from("test-2"), 6)],
+        "list mismatch"
+    );
+    let etag = files
+        .iter()
+        .find(|meta| meta.path == Path::new("test-1"))
+        .and_then(|meta| meta.etag.clone());
+    assert!(etag.is_some(), "missing etag");
+    assert_eq!(
+        storage
+            .stat(Path::new("test-1"))
+            .await
+            .expect("stat failed")
+            .etag,
+        etag,
+        "etag mismatch"
+    );
+    assert_eq!(
+        storage.list("".as_ref()).await.expect("list failed").len(),
+        3,
+        "list mismatch"
+    );
 
     if strict {
         assert!(
@@ -159,7 +284,7 @@ async fn must_provider_work(storage: impl StorageProvider, strict: bool) {
         .get_file("test-2".as_ref())
         .await
         .expect("get failed");
-    assert!(!stream_2.in_memory());
+    assert_eq!(stream_2.in_memory(), !spill);
     let mut read_buf = vec![];
     stream_2
         .read_to_end(&mut read_buf)
@@ -167,6 +292,57 @@ async fn must_provider_work(storage: impl StorageProvider, strict: bool) {
         .expect("read failed");
     assert_eq!(read_buf, [1, 2, 3, 4, 5, 6], "content mismatch");
 
+    let mut range = storage
+        .get_range("test-2".as_ref(), 1..4)
+        .await
+        .expect("get range failed");
+    let mut read_buf = vec![];
+    range.read_to_end(&mut read_buf).await.expect("read failed");
+    assert_eq!(read_buf, [2, 3, 4], "range mismatch");
+
+    if strict {
+        assert!(
+            matches!(
+                storage
+                    .put_file("test-1".as_ref(), vec![5].into())
+                    .await
+                    .unwrap_err(),
+                StorageError::FileExists(_)
+            ),
+            "overwriting by put"
+        );
+    }
+    storage
+        .replace_file("test-1".as_ref(), vec![5, 4, 3].into())
+        .await
+        .expect("replace failed");
+    storage
+        .replace_file("test-3".as_ref(), vec![6].into())
+        .await
+        .expect("replace failed");
+    let mut read_buf = vec![];
+    storage
+        .get_file("test-1".as_ref())
+        .await
+        .expect("get failed")
+        .read_to_end(&mut read_buf)
+        .await
+        .expect("read failed");
+    assert_eq!(read_buf, [5, 4, 3], "content mismatch");
+    assert_eq!(
+        storage
+            .stat("test-3".as_ref())
+            .await
+            .expect("stat failed")
+            .size,
+        1,
+        "size mismatch"
+    );
+    storage
+        .delete_file("test-3".as_ref())
+        .await
+        .expect("delete failed");
+
     storage
         .delete_file("test-2".as_ref())
         .await
@@ -183,26 +359,201 @@ async fn must_provider_work(storage: impl StorageProvider, strict: bool) {
 #[tokio::test]
 async fn test_fs_provider() {
     let test_dir = tempdir().expect("temp dir creation failed");
-    let fs_storage = FSStorage::new_with_limit(test_dir.path(), 5);
+    let fs_storage = Arc::new(FSStorage::new_with_limit(test_dir.path(), 5));
+
+    must_provider_work(fs_storage.clone(), true, true).await;
+
+    // walk starts from the directory of prefix
+    std::fs::create_dir_all(test_dir.path().join("nested/dir")).unwrap();
+    std::fs::write(test_dir.path().join("nested/dir/test-1"), b"").unwrap();
+    std::fs::write(test_dir.path().join("nested/other"), b"").unwrap();
+    assert_eq!(
+        fs_storage
+            .list("nested/dir/te".as_ref())
+            .await
+            .expect("list failed")
+            .into_iter()
+            .map(|meta| meta.path)
+            .collect_vec(),
+        vec![PathBuf::from("nested/dir/test-1")],
+        "list mismatch"
+    );
+    assert_eq!(
+        fs_storage
+            .list("nested/".as_ref())
+            .await
+            .expect("list failed")
+            .len(),
+        2,
+        "list mismatch"
+    );
+    std::fs::remove_dir_all(test_dir.path().join("nested")).unwrap();
+
+    // nothing is left behind by atomic writes
+    let leftovers = std::fs::read_dir(test_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|name| name.to_string_lossy().starts_with(".partial-"))
+        .count();
+    assert_eq!(leftovers, 0, "temp files left behind");
+}
 
-    must_provider_work(fs_storage, true).await
+async fn must_s3_multipart_work(storage: S3Storage) {
+    // 3 parts: 5M + 5M + 1M
+    let mut data = vec![0u8; 11 * 1024 * 1024];
+    thread_rng().fill_bytes(&mut data);
+
+    storage
+        .put_file(Path::new("multipart"), ByteStream::from(data.clone()))
+        .await
+        .expect("unable to put file");
+    assert_eq!(
+        storage
+            .stat(Path::new("multipart"))
+            .await
+            .expect("unable to stat file")
+            .size,
+        data.len() as u64
+    );
+
+    // fetched by ranges, nothing is buffered on disk
+    let mut stream = storage
+        .get_file(Path::new("multipart"))
+        .await
+        .expect("unable to get stream");
+    assert!(!stream.in_memory(), "large object read into memory");
+    let mut buf = vec![];
+    stream
+        .read_to_end(&mut buf)
+        .await
+        .expect("unable to read stream");
+    assert_eq!(buf, data, "stream content mismatch");
+
+    let range = 5 * 1024 * 1024 - 10..5 * 1024 * 1024 + 10;
+    let mut buf = vec![];
+    storage
+        .get_range(Path::new("multipart"), range.clone())
+        .await
+        .expect("unable to get range")
+        .read_to_end(&mut buf)
+        .await
+        .expect("unable to read range");
+    assert_eq!(buf, &data[range.start as usize..range.end as usize]);
+}
+
+async fn must_s3_resume_upload(storage: S3Storage) {
+    let mut data = vec![0u8; 11 * 1024 * 1024];
+    thread_rng().fill_bytes(&mut data);
+
+    // an upload interrupted after the first part
+    let upload_id = storage
+        .create_upload("resume", None)
+        .await
+        .expect("unable to create upload");
+    storage
+        .upload_part(
+            "resume",
+            &upload_id,
+            1,
+            ByteStream::from(data[..5 * 1024 * 1024].to_vec()),
+        )
+        .await
+        .expect("unable to upload part");
+    assert_eq!(
+        storage
+            .find_upload("resume")
+            .await
+            .expect("unable to find upload"),
+        Some(upload_id),
+        "upload not found"
+    );
+
+    storage
+        .put_file(Path::new("resume"), ByteStream::from(data.clone()))
+        .await
+        .expect("unable to put file");
+    assert_eq!(
+        storage
+            .find_upload("resume")
+            .await
+            .expect("unable to find upload"),
+        None,
+        "upload not completed"
+    );
+    let mut buf = vec![];
+    storage
+        .get_file(Path::new("resume"))
+        .await
+        .expect("unable to get file")
+        .read_to_end(&mut buf)
+        .await
+        .expect("unable to read file");
+    assert_eq!(buf, data, "content mismatch");
+}
+
+#[tokio::test]
+async fn test_memory_provider() {
+    must_provider_work(MemoryStorage::new(), true, false).await
+}
+
+#[tokio::test]
+async fn test_url_provider() {
+    let test_dir = tempdir().expect("temp dir creation failed");
+    let url = Url::from_directory_path(test_dir.path()).unwrap();
+    must_provider_work(
+        from_url(&format!("{}?memory_limit=5", url)).expect("unable to parse url"),
+        true,
+        true,
+    )
+    .await;
+    must_provider_work(
+        from_url("memory://").expect("unable to parse url"),
+        true,
+        false,
+    )
+    .await
+}
+
+#[rstest]
+#[case("file:///srv/repo", true)]
+#[case("file:///srv/repo?memory_limit=abc", false)]
+#[case("memory://", true)]
+#[case("s3://bucket/prefix?region=eu-west-1", true)]
+#[case("s3://bucket/my%20prefix?region=eu-west-1", true)]
+#[case("s3://bucket/%FF", false)]
+#[case(
+    "s3://bucket?endpoint=http://localhost:9090&region=mock-s3&part_size=5242880",
+    true
+)]
+#[case("s3://bucket?part_size=1024", false)]
+#[case("s3://bucket?region=eu-west-1&addressing=path", true)]
+#[case("s3://bucket?addressing=virtual-hosted", false)]
+#[case("s3://bucket?addressing=other", false)]
+#[case("s3://bucket?unknown=1", false)]
+#[case("s3://key:secret@bucket", false)]
+#[case("s3:///prefix", false)]
+#[case("ftp://host/path", false)]
+fn must_parse_storage_url(#[case] url: &str, #[case] ok: bool) {
+    let result = from_url(url);
+    assert_eq!(result.is_ok(), ok, "{}", url);
 }
 
 #[tokio::test]
 async fn test_s3_provider() {
     let s3_storage = S3StorageBuilder::new()
-        .with_name("mock-s3")
+        .with_region("mock-s3")
         .with_bucket("test-bucket")
         .with_credential("", "")
-        .with_memory_limit(5);
+        .with_memory_limit(5)
+        .with_part_size(5 * 1024 * 1024)
+        .with_concurrency(2);
 
     if let Some(endpoint) = option_env!("S3_ENDPOINT") {
-        let s3_storage = s3_storage
-            .with_endpoint(endpoint)
-            .build()
-            .expect("unable to build s3 storage");
+        let s3_storage = s3_storage.with_endpoint(endpoint);
 
-        must_provider_work(s3_storage, false).await
+        must_provider_work(s3_storage.clone().build().unwrap(), false, true).await;
+        must_s3_multipart_work(s3_storage.clone().build().unwrap()).await;
+        must_s3_resume_upload(s3_storage.build().unwrap()).await
     } else {
         let client = Arc::new(clients::Cli::default());
         let image = GenericImage::new("adobe/s3mock")
@@ -211,15 +562,65 @@ async fn test_s3_provider() {
         let args = RunArgs::default().with_mapped_port((9090, 9090));
         let _container = client.run_with_args(image, args);
 
-        let s3_storage = s3_storage
-            .with_endpoint("http://localhost:9090")
-            .build()
-            .expect("unable to build s3 storage");
+        let s3_storage = s3_storage.with_endpoint("http://localhost:9090");
 
-        must_provider_work(s3_storage, false).await
+        must_provider_work(s3_storage.clone().build().unwrap(), false, true).await;
+        must_s3_multipart_work(s3_storage.clone().build().unwrap()).await;
+        must_s3_resume_upload(s3_storage.build().unwrap()).await
     }
 }
 
+#[rstest]
+#[case(S3StorageBuilder::new().with_region("eu-west-1"), true)]
+#[case(S3StorageBuilder::new().with_region("mars-north-1"), false)]
+#[case(S3StorageBuilder::new().with_region("mock-s3").with_endpoint("http://localhost:9090"), true)]
+#[case(S3StorageBuilder::new().with_addressing(S3Addressing::VirtualHosted), false)]
+#[case(S3StorageBuilder::new().with_part_size(1024), false)]
+fn must_build_s3_storage(#[case] builder: S3StorageBuilder, #[case] ok: bool) {
+    let builder = builder
+        .with_bucket("test-bucket")
+        .with_session_credential("key", "secret", "token");
+    assert_eq!(builder.build().is_ok(), ok);
+}
+
+#[test]
+fn must_parse_web_identity_credentials() {
+    let body = r#"<AssumeRoleWithWebIdentityResponse xmlns="https://sts.amazonaws.com/doc/2011-06-15/">
+  <AssumeRoleWithWebIdentityResult>
+    <Credentials>
+      <AccessKeyId>ASIAEXAMPLE</AccessKeyId>
+      <SecretAccessKey>secret</SecretAccessKey>
+      <SessionToken>token</SessionToken>
+      <Expiration>2022-01-01T00:00:00Z</Expiration>
+    </Credentials>
+  </AssumeRoleWithWebIdentityResult>
+</AssumeRoleWithWebIdentityResponse>"#;
+    let credentials = parse_credentials(body).expect("unable to parse credentials");
+    assert_eq!(credentials.aws_access_key_id(), "ASIAEXAMPLE");
+    assert_eq!(credentials.aws_secret_access_key(), "secret");
+    assert_eq!(credentials.token().as_deref(), Some("token"));
+    assert!(credentials.expires_at().is_some(), "missing expiration");
+
+    assert!(parse_credentials("<Error><Code>AccessDenied</Code></Error>").is_err());
+
+    // regional endpoints are used for standard regions
+    assert_eq!(
+        sts_endpoint(&Region::EuWest1),
+        "https://sts.eu-west-1.amazonaws.com/"
+    );
+    assert_eq!(
+        sts_endpoint(&Region::CnNorth1),
+        "https://sts.cn-north-1.amazonaws.com.cn/"
+    );
+    assert_eq!(
+        sts_endpoint(&Region::Custom {
+            name: String::from("mock-s3"),
+            endpoint: String::from("http://localhost:9090"),
+        }),
+        "https://sts.amazonaws.com/"
+    );
+}
+
 #[derive(Default)]
 struct MockProvider {
     seq: Mutex<Vec<PathBuf>>,
@@ -253,6 +654,10 @@ impl StorageProvider for MockProvider {
         panic!("get_file not supported")
     }
 
+    async fn stat(&self, _path: &Path) -> Result<FileMeta> {
+        panic!("stat not supported")
+    }
+
     async fn put_file(&self, path: &Path, _data: ByteStream) -> Result<()> {
         tokio::time::sleep(Duration::from_millis((random::<f32>() * 50.) as u64)).await;
         self.seq.lock().unwrap().push(path.to_path_buf());
@@ -266,6 +671,10 @@ impl StorageProvider for MockProvider {
         tokio::time::sleep(Duration::from_millis((random::<f32>() * 20.) as u64)).await;
         Ok(())
     }
+
+    async fn list(&self, _prefix: &Path) -> Result<Vec<FileMeta>> {
+        panic!("list not supported")
+    }
 }
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 6)]
@@ -375,3 +784,1059 @@ fn test_lockfile() {
 
     assert_eq!(meta_map, de_map, "map mismatch");
 }
+
+#[tokio::test]
+async fn must_verify_repo() {
+    let dir = tempdir().expect("unable to create temp dir");
+    let pkgs = [
+        "a52dec-0.7.4-11-x86_64.pkg.tar.zst",
+        "aalib-1.4rc5-14-x86_64.pkg.tar.zst",
+        "acl-2.3.1-1-x86_64.pkg.tar.zst",
+    ];
+    let mut builder = DBBuilder::new();
+    for pkg in pkgs {
+        builder.add_file_mut(Path::new("tests/pkgs").join(pkg));
+    }
+    builder
+        .build(
+            BuildTarget::new(dir.path(), Some("test"))
+                .with_options(ArchiveOptions::new().link(DBLink::Copy)),
+        )
+        .expect("unable to build db");
+
+    // a52dec is intact, aalib is missing, acl is corrupted
+    std::fs::copy(
+        Path::new("tests/pkgs").join(pkgs[0]),
+        dir.path().join(pkgs[0]),
+    )
+    .expect("unable to copy package");
+    let mut corrupted =
+        std::fs::read(Path::new("tests/pkgs").join(pkgs[2])).expect("unable to read package");
+    corrupted.push(0);
+    std::fs::write(dir.path().join(pkgs[2]), corrupted).expect("unable to write package");
+
+    // pending.pkg.tar.zst is tracked by lock file but not referenced by db yet
+    let mut lock_file = LockFile::new();
+    lock_file.packages.insert(RemotePackageUnit {
+        meta: PackageMeta::new("pending", &Version(String::from("1.0-1")), 0),
+        key: PathBuf::from("pending.pkg.tar.zst"),
+        sha256: None,
+    });
+    lock_file.packages.insert(RemotePackageUnit {
+        meta: PackageMeta::new("a52dec", &Version(String::from("0.7.4-11")), 0),
+        key: PathBuf::from(pkgs[0]),
+        sha256: None,
+    });
+    std::fs::write(
+        dir.path().join("index.lock"),
+        serde_json::to_vec(&lock_file).unwrap(),
+    )
+    .expect("unable to write lock file");
+
+    // orphan.pkg.tar.zst is referenced by neither db nor lock file
+    let sig = format!("{}.sig", pkgs[0]);
+    for file in ["pending.pkg.tar.zst", "orphan.pkg.tar.zst", sig.as_str()] {
+        std::fs::write(dir.path().join(file), b"").expect("unable to write file");
+    }
+
+    let storage = FSStorage::new(dir.path());
+    let report = verify_repo(&storage, Path::new("test.db"))
+        .await
+        .expect("unable to verify repo");
+    assert!(!report.is_ok());
+    assert_eq!(report.checked, 2, "checked count mismatch");
+    assert_eq!(
+        report.issues.into_iter().collect::<HashSet<_>>(),
+        HashSet::from([
+            VerifyIssue::MissingFile {
+                name: String::from("aalib"),
+                key: PathBuf::from(pkgs[1]),
+            },
+            VerifyIssue::SizeMismatch {
+                name: String::from("acl"),
+                expected: 139_672,
+                actual: 139_673,
+            },
+            VerifyIssue::MD5Mismatch {
+                name: String::from("acl"),
+            },
+            VerifyIssue::SHA256Mismatch {
+                name: String::from("acl"),
+            },
+            VerifyIssue::OrphanedFile(PathBuf::from("orphan.pkg.tar.zst")),
+        ]),
+        "issue mismatch"
+    );
+}
+
+#[rstest]
+#[case("../acl-2.3.1-1-x86_64.pkg.tar.zst")]
+#[case("pkgs/acl-2.3.1-1-x86_64.pkg.tar.zst")]
+#[case("/tmp/acl-2.3.1-1-x86_64.pkg.tar.zst")]
+#[tokio::test]
+async fn must_reject_invalid_file_name(#[case] file_name: &str) {
+    let dir = tempdir().expect("unable to create temp dir");
+    let (mut desc, files) =
+        DBBuilder::index_package(Path::new("tests/pkgs/acl-2.3.1-1-x86_64.pkg.tar.zst"))
+            .expect("unable to index package");
+    desc.file_name = file_name.to_string();
+    let mut editor = DBEditor::new();
+    editor
+        .add_entry(&desc, &files)
+        .expect("unable to add entry");
+    editor
+        .build(
+            BuildTarget::new(dir.path(), Some("test"))
+                .with_options(ArchiveOptions::new().link(DBLink::Copy)),
+        )
+        .expect("unable to build db");
+
+    let storage = FSStorage::new(dir.path());
+    let report = verify_repo(&storage, Path::new("test.db"))
+        .await
+        .expect("unable to verify repo");
+    assert_eq!(report.checked, 0, "checked count mismatch");
+    assert_eq!(
+        report.issues,
+        vec![VerifyIssue::InvalidFileName {
+            name: String::from("acl"),
+            file_name: file_name.to_string(),
+        }],
+        "issue mismatch"
+    );
+}
+
+#[rstest]
+#[case(Compression::Zstd)]
+#[case(Compression::Xz)]
+#[tokio::test]
+async fn must_publish_db(#[case] compression: Compression) {
+    let remote_dir = tempdir().expect("unable to create temp dir");
+    let local_dir = tempdir().expect("unable to create temp dir");
+    let mut pool = PackagePool::new(
+        FSStorage::new(remote_dir.path()),
+        local_dir.path().to_path_buf(),
+    )
+    .with_repo("test")
+    .with_db_options(ArchiveOptions::new().compression(compression));
+
+    let stage = |pool: &mut PackagePool<FSStorage>, name: &str, version: &str, file: &str| {
+        pool.stage(LocalPackageUnit::new(
+            PackageMeta::new(name, &Version(String::from(version)), 0xdeadbeef),
+            Path::new("tests/pkgs").join(file),
+        ));
+    };
+
+    stage(
+        &mut pool,
+        "a52dec",
+        "0.7.4-11",
+        "a52dec-0.7.4-11-x86_64.pkg.tar.zst",
+    );
+    stage(
+        &mut pool,
+        "acl",
+        "2.3.1-1",
+        "acl-2.3.1-1-x86_64.pkg.tar.zst",
+    );
+    pool.commit().await.expect("unable to commit");
+
+    let db = PacmanDB::open(remote_dir.path(), "test", true).expect("unable to read db");
+    assert_eq!(db.len(), 2, "package count mismatch");
+    assert_eq!(
+        db["acl"].desc.file_name, "acl-2.3.1-1-deadbeef.tar.zst",
+        "file name mismatch"
+    );
+    assert!(remote_dir.path().join(&db["acl"].desc.file_name).exists());
+    assert!(db["acl"].files.is_some(), "missing file list");
+    for kind in ["db", "files"] {
+        let archive = format!("test.{}.{}", kind, compression.extension());
+        assert!(remote_dir.path().join(archive).exists(), "missing archive");
+    }
+
+    // database is updated incrementally
+    stage(
+        &mut pool,
+        "aalib",
+        "1.4rc5-14",
+        "aalib-1.4rc5-14-x86_64.pkg.tar.zst",
+    );
+    pool.commit().await.expect("unable to commit");
+
+    let db = PacmanDB::open(remote_dir.path(), "test", false).expect("unable to read db");
+    assert_eq!(
+        db.iter()
+            .map(|entry| entry.desc.name.as_str())
+            .sorted()
+            .collect_vec(),
+        vec!["a52dec", "aalib", "acl"],
+        "package list mismatch"
+    );
+}
+
+#[tokio::test]
+async fn must_index_published_packages_without_db() {
+    let remote_dir = tempdir().expect("unable to create temp dir");
+    let local_dir = tempdir().expect("unable to create temp dir");
+
+    // published by a pool which doesn't publish databases
+    let mut pool = PackagePool::new(
+        FSStorage::new(remote_dir.path()),
+        local_dir.path().to_path_buf(),
+    );
+    for (version, file) in [
+        ("2.3.0-1", "acl-2.3.1-1-x86_64.pkg.tar.zst"),
+        ("2.3.1-1", "acl-2.3.1-1-x86_64.pkg.tar.zst"),
+    ] {
+        pool.stage(LocalPackageUnit::new(
+            PackageMeta::new("acl", &Version(String::from(version)), 0),
+            Path::new("tests/pkgs").join(file),
+        ));
+        pool.commit().await.expect("unable to commit");
+    }
+
+    let mut pool = pool.with_repo("test");
+    pool.stage(LocalPackageUnit::new(
+        PackageMeta::new("aalib", &Version(String::from("1.4rc5-14")), 0),
+        "tests/pkgs/aalib-1.4rc5-14-x86_64.pkg.tar.zst",
+    ));
+    pool.commit().await.expect("unable to commit");
+
+    // packages published before are indexed too, only their newest versions
+    let db = PacmanDB::open(remote_dir.path(), "test", false).expect("unable to read db");
+    assert_eq!(
+        db.iter()
+            .map(|entry| entry.desc.name.as_str())
+            .sorted()
+            .collect_vec(),
+        vec!["aalib", "acl"],
+        "package list mismatch"
+    );
+    assert_eq!(
+        db["acl"].desc.file_name,
+        format!(
+            "{}.tar.zst",
+            PackageMeta::new("acl", &Version(String::from("2.3.1-1")), 0).filename()
+        ),
+        "file name mismatch"
+    );
+}
+
+#[tokio::test]
+async fn must_sync_pool() {
+    let remote_dir = tempdir().expect("unable to create temp dir");
+    let local_dir = tempdir().expect("unable to create temp dir");
+
+    // missing lock file is an empty repo
+    let mut pool = PackagePool::open(
+        FSStorage::new(remote_dir.path()),
+        local_dir.path().to_path_buf(),
+    )
+    .await
+    .expect("unable to open pool");
+    let acl = PackageMeta::new("acl", &Version(String::from("2.3.1-1")), 1);
+    pool.stage(LocalPackageUnit::new(
+        &acl,
+        "tests/pkgs/acl-2.3.1-1-x86_64.pkg.tar.zst",
+    ));
+    pool.commit().await.expect("unable to commit");
+
+    // a fresh pool knows what's already published
+    let other_local_dir = tempdir().expect("unable to create temp dir");
+    let mut pool = PackagePool::open(
+        FSStorage::new(remote_dir.path()),
+        other_local_dir.path().to_path_buf(),
+    )
+    .await
+    .expect("unable to open pool");
+    let path = pool
+        .get(&acl)
+        .await
+        .expect("unable to get package")
+        .expect("missing package");
+    assert_eq!(
+        std::fs::read(path).unwrap(),
+        std::fs::read("tests/pkgs/acl-2.3.1-1-x86_64.pkg.tar.zst").unwrap(),
+        "content mismatch"
+    );
+
+    // commit keeps previously published packages
+    let aalib = PackageMeta::new("aalib", &Version(String::from("1.4rc5-14")), 2);
+    pool.stage(LocalPackageUnit::new(
+        &aalib,
+        "tests/pkgs/aalib-1.4rc5-14-x86_64.pkg.tar.zst",
+    ));
+    pool.commit().await.expect("unable to commit");
+    let lock_file: LockFile =
+        serde_json::from_slice(&std::fs::read(remote_dir.path().join("index.lock")).unwrap())
+            .expect("unable to parse lock file");
+    assert_eq!(
+        lock_file
+            .packages
+            .into_iter()
+            .map(|unit| unit.meta)
+            .collect::<HashSet<_>>(),
+        HashSet::from([acl, aalib]),
+        "lock file mismatch"
+    );
+
+    // unknown lock file version
+    let mut lock_file = LockFile::new();
+    lock_file.version = LOCK_FILE_VERSION + 1;
+    std::fs::write(
+        remote_dir.path().join("index.lock"),
+        serde_json::to_vec(&lock_file).unwrap(),
+    )
+    .unwrap();
+    assert!(matches!(
+        PackagePool::open(
+            FSStorage::new(remote_dir.path()),
+            local_dir.path().to_path_buf()
+        )
+        .await,
+        Err(StorageError::UnsupportedLockVersion(_))
+    ));
+}
+
+#[tokio::test]
+async fn must_gc_pool() {
+    let remote_dir = tempdir().expect("unable to create temp dir");
+    let local_dir = tempdir().expect("unable to create temp dir");
+    let mut pool = PackagePool::new(
+        FSStorage::new(remote_dir.path()),
+        local_dir.path().to_path_buf(),
+    );
+
+    let metas = (1..=3)
+        .map(|i| PackageMeta::new("acl", &Version(format!("2.3.{}-1", i)), i))
+        .collect_vec();
+    for meta in &metas {
+        pool.stage(LocalPackageUnit::new(
+            meta,
+            "tests/pkgs/acl-2.3.1-1-x86_64.pkg.tar.zst",
+        ));
+        pool.commit().await.expect("unable to commit");
+    }
+    std::fs::write(remote_dir.path().join("stray"), b"stray").unwrap();
+    // left behind by an interrupted upload
+    std::fs::write(remote_dir.path().join(".partial-stray"), b"stray").unwrap();
+    let key_of = |meta: &PackageMeta| PathBuf::from(format!("{}.tar.zst", meta.filename()));
+    let sig_of = |meta: &PackageMeta| PathBuf::from(format!("{}.tar.zst.sig", meta.filename()));
+    for meta in &metas {
+        std::fs::write(remote_dir.path().join(sig_of(meta)), b"sig").unwrap();
+    }
+
+    // fresh files may belong to an in-flight commit
+    let report = pool
+        .gc(RetentionPolicy::KeepAll, true)
+        .await
+        .expect("unable to gc");
+    assert!(report.deleted.is_empty(), "fresh files deleted");
+    let mut pool = pool.with_gc_grace(chrono::Duration::zero());
+
+    // nothing expires within a day
+    let report = pool
+        .gc(RetentionPolicy::KeepFor(chrono::Duration::days(1)), true)
+        .await
+        .expect("unable to gc");
+    assert!(report.expired.is_empty(), "unexpected expired packages");
+    assert_eq!(
+        report.deleted.into_iter().sorted().collect_vec(),
+        vec![PathBuf::from(".partial-stray"), PathBuf::from("stray")]
+    );
+
+    // dry run doesn't touch storage
+    let report = pool
+        .gc(RetentionPolicy::KeepLast(2), true)
+        .await
+        .expect("unable to gc");
+    assert_eq!(report.expired, vec![metas[0].clone()], "expired mismatch");
+    assert_eq!(
+        report.deleted.into_iter().sorted().collect_vec(),
+        vec![
+            PathBuf::from(".partial-stray"),
+            key_of(&metas[0]),
+            sig_of(&metas[0]),
+            PathBuf::from("stray")
+        ],
+        "deleted mismatch"
+    );
+    assert!(remote_dir.path().join(key_of(&metas[0])).exists());
+
+    pool.gc(RetentionPolicy::KeepLast(2), false)
+        .await
+        .expect("unable to gc");
+    assert!(!remote_dir.path().join(key_of(&metas[0])).exists());
+    assert!(!remote_dir.path().join("stray").exists());
+    assert!(!remote_dir.path().join(".partial-stray").exists());
+    assert!(remote_dir.path().join(key_of(&metas[2])).exists());
+    assert!(!remote_dir.path().join(sig_of(&metas[0])).exists());
+    assert!(remote_dir.path().join(sig_of(&metas[2])).exists());
+    let lock_file: LockFile =
+        serde_json::from_slice(&std::fs::read(remote_dir.path().join("index.lock")).unwrap())
+            .expect("unable to parse lock file");
+    assert_eq!(
+        lock_file
+            .packages
+            .into_iter()
+            .map(|unit| unit.meta)
+            .collect::<HashSet<_>>(),
+        metas[1..].iter().cloned().collect(),
+        "lock file mismatch"
+    );
+}
+
+#[rstest]
+#[case(1, true, Recovery::RolledForward)]
+#[case(1, false, Recovery::RolledBack)]
+#[case(2, true, Recovery::RolledForward)]
+#[tokio::test]
+async fn must_recover_txn(
+    #[case] failures: usize,
+    #[case] fail_inlined: bool,
+    #[case] expect: Recovery,
+) {
+    let dir = tempdir().expect("unable to create temp dir");
+    let mut big_file = NamedTempFile::new().expect("unable to create temp file");
+    big_file.write_all(&[0; 16]).unwrap();
+    let big = ByteStream::try_from(big_file).unwrap();
+
+    let fail_key = PathBuf::from(if fail_inlined { "small-2" } else { "big" });
+    let storage = FaultyStorage::new(FSStorage::new(dir.path())).with_fault(
+        FaultRule::new(Operation::Put, Fault::Fail)
+            .key(fail_key)
+            .times(Some(failures)),
+    );
+
+    let mut txn = Txn::with_journal("journal");
+    txn.add(TxnAction::Put("small-1".into(), setup_memory_bytestream()));
+    txn.add(TxnAction::Put("big".into(), big));
+    txn.add(TxnAction::Barrier);
+    txn.add(TxnAction::Put("small-2".into(), setup_memory_bytestream()));
+    assert!(txn.commit(&storage).await.is_err(), "commit must fail");
+
+    if failures > 1 {
+        // recovery during commit failed, so it's done on the next start
+        assert!(dir.path().join("journal").exists(), "missing journal");
+        assert_eq!(
+            Txn::recover(&storage, Path::new("journal"))
+                .await
+                .expect("unable to recover"),
+            expect,
+            "recovery mismatch"
+        );
+    }
+    assert_eq!(
+        Txn::recover(&storage, Path::new("journal"))
+            .await
+            .expect("unable to recover"),
+        Recovery::Clean,
+        "journal not cleaned"
+    );
+
+    let files = storage
+        .list("".as_ref())
+        .await
+        .expect("list failed")
+        .into_iter()
+        .map(|meta| meta.path)
+        .sorted()
+        .collect_vec();
+    let expect_files: Vec<PathBuf> = match expect {
+        Recovery::RolledForward => vec!["big".into(), "small-1".into(), "small-2".into()],
+        _ => vec![],
+    };
+    assert_eq!(files, expect_files, "storage content mismatch");
+}
+
+#[rstest]
+#[case(1)]
+#[case(2)]
+#[tokio::test]
+async fn must_restore_replaced_files(#[case] failures: usize) {
+    let dir = tempdir().expect("unable to create temp dir");
+    let file_stream = |data: &[u8]| {
+        let mut file = NamedTempFile::new().expect("unable to create temp file");
+        file.write_all(data).unwrap();
+        ByteStream::try_from(file).unwrap()
+    };
+    for key in ["test.db", "test.files"] {
+        std::fs::write(dir.path().join(key), b"old").unwrap();
+    }
+
+    // db is replaced, then the interrupted files stage can't be replayed
+    let storage = FaultyStorage::new(FSStorage::new(dir.path())).with_fault(
+        FaultRule::new(Operation::Put, Fault::Fail)
+            .key("test.files")
+            .times(Some(failures)),
+    );
+    let mut txn = Txn::with_journal("journal");
+    txn.add(TxnAction::Replace("test.db".into(), file_stream(b"new")));
+    txn.add(TxnAction::Barrier);
+    txn.add(TxnAction::Replace("test.files".into(), file_stream(b"new")));
+    txn.add(TxnAction::Put("new.pkg".into(), file_stream(b"new")));
+    assert!(txn.commit(&storage).await.is_err(), "commit must fail");
+
+    if failures > 1 {
+        // restoring during commit failed, so it's done on the next start
+        assert_eq!(
+            Txn::recover(&storage, Path::new("journal"))
+                .await
+                .expect("unable to recover"),
+            Recovery::RolledBack,
+            "recovery mismatch"
+        );
+    }
+
+    let files = storage
+        .list("".as_ref())
+        .await
+        .expect("list failed")
+        .into_iter()
+        .map(|meta| meta.path)
+        .sorted()
+        .collect_vec();
+    assert_eq!(
+        files,
+        vec![PathBuf::from("test.db"), PathBuf::from("test.files")],
+        "storage content mismatch"
+    );
+    for key in ["test.db", "test.files"] {
+        assert_eq!(std::fs::read(dir.path().join(key)).unwrap(), b"old");
+    }
+}
+
+#[tokio::test]
+async fn must_keep_lock_file_readable() {
+    let remote_dir = tempdir().expect("unable to create temp dir");
+    let local_dir = tempdir().expect("unable to create temp dir");
+    let mut pool = PackagePool::new(
+        FSStorage::new(remote_dir.path()),
+        local_dir.path().to_path_buf(),
+    );
+    let acl = PackageMeta::new("acl", &Version(String::from("2.3.1-1")), 1);
+    pool.stage(LocalPackageUnit::new(
+        &acl,
+        "tests/pkgs/acl-2.3.1-1-x86_64.pkg.tar.zst",
+    ));
+    pool.commit().await.expect("unable to commit");
+    let aalib = PackageMeta::new("aalib", &Version(String::from("1.4rc5-14")), 2);
+    pool.stage(LocalPackageUnit::new(
+        &aalib,
+        "tests/pkgs/aalib-1.4rc5-14-x86_64.pkg.tar.zst",
+    ));
+    pool.commit().await.expect("unable to commit");
+    assert!(remote_dir.path().join("index.lock.bak").exists());
+    assert!(!remote_dir.path().join("txn.journal").exists());
+
+    // simulate a lost lock file, e.g. on storages without atomic replacement
+    std::fs::remove_file(remote_dir.path().join("index.lock")).unwrap();
+    let mut pool = PackagePool::open(
+        FSStorage::new(remote_dir.path()),
+        local_dir.path().to_path_buf(),
+    )
+    .await
+    .expect("unable to open pool");
+    assert!(pool
+        .get(&acl)
+        .await
+        .expect("unable to get package")
+        .is_some());
+    assert!(pool
+        .get(&aalib)
+        .await
+        .expect("unable to get package")
+        .is_none());
+}
+
+#[rstest]
+#[case(0, true)]
+#[case(2, true)]
+#[case(4, false)]
+#[tokio::test]
+async fn must_verify_download(#[case] failures: usize, #[case] ok: bool) {
+    let remote_dir = tempdir().expect("unable to create temp dir");
+    let local_dir = tempdir().expect("unable to create temp dir");
+
+    let acl = PackageMeta::new("acl", &Version(String::from("2.3.1-1")), 1);
+    let mut pool = PackagePool::new(
+        FSStorage::new(remote_dir.path()),
+        local_dir.path().to_path_buf(),
+    );
+    pool.stage(LocalPackageUnit::new(
+        &acl,
+        "tests/pkgs/acl-2.3.1-1-x86_64.pkg.tar.zst",
+    ));
+    pool.commit().await.expect("unable to commit");
+
+    let lock_file: LockFile =
+        serde_json::from_slice(&std::fs::read(remote_dir.path().join("index.lock")).unwrap())
+            .expect("unable to parse lock file");
+    let sha256 = DBBuilder::checksum(
+        std::fs::File::open("tests/pkgs/acl-2.3.1-1-x86_64.pkg.tar.zst").unwrap(),
+    )
+    .unwrap()
+    .sha256;
+    assert_eq!(
+        lock_file
+            .packages
+            .iter()
+            .map(|unit| unit.sha256.as_deref())
+            .collect_vec(),
+        vec![Some(sha256.as_str())],
+        "lock file digest mismatch"
+    );
+
+    // lock file is read on open, start corrupting after that
+    let storage = Arc::new(FaultyStorage::new(FSStorage::new(remote_dir.path())));
+    let other_local_dir = tempdir().expect("unable to create temp dir");
+    let mut pool = PackagePool::open(storage.clone(), other_local_dir.path().to_path_buf())
+        .await
+        .expect("unable to open pool");
+    // downloads are truncated for the first N times
+    storage.add_fault(FaultRule::new(Operation::Get, Fault::Corrupt).times(Some(failures)));
+
+    let result = pool.get(&acl).await;
+    if ok {
+        let path = result
+            .expect("unable to get package")
+            .expect("missing package");
+        assert_eq!(
+            std::fs::read(path).unwrap(),
+            std::fs::read("tests/pkgs/acl-2.3.1-1-x86_64.pkg.tar.zst").unwrap(),
+            "content mismatch"
+        );
+    } else {
+        assert!(matches!(result, Err(StorageError::ChecksumMismatch(_))));
+        // nothing is left in local cache
+        assert_eq!(
+            std::fs::read_dir(other_local_dir.path()).unwrap().count(),
+            0,
+            "corrupt file in local cache"
+        );
+    }
+}
+
+#[tokio::test]
+async fn must_evict_local_cache() {
+    let remote_dir = tempdir().expect("unable to create temp dir");
+    let local_dir = tempdir().expect("unable to create temp dir");
+
+    let pkgs = [
+        ("acl", "2.3.1-1"),
+        ("aalib", "1.4rc5-14"),
+        ("a52dec", "0.7.4-11"),
+    ];
+    let metas = pkgs
+        .iter()
+        .map(|(name, version)| PackageMeta::new(name, &Version(version.to_string()), 0))
+        .collect_vec();
+    let publish_dir = tempdir().expect("unable to create temp dir");
+    let mut pool = PackagePool::new(
+        FSStorage::new(remote_dir.path()),
+        publish_dir.path().to_path_buf(),
+    );
+    for ((name, version), meta) in pkgs.iter().zip(&metas) {
+        pool.stage(LocalPackageUnit::new(
+            meta,
+            format!("tests/pkgs/{}-{}-x86_64.pkg.tar.zst", name, version),
+        ));
+    }
+    pool.commit().await.expect("unable to commit");
+
+    // acl (139672) + aalib (139582) + a52dec (39197) don't fit
+    let mut pool = PackagePool::new(
+        FSStorage::new(remote_dir.path()),
+        local_dir.path().to_path_buf(),
+    )
+    .with_cache_limit(300_000);
+    pool.sync().await.expect("unable to sync");
+    for idx in [0, 1, 0, 2] {
+        pool.get(&metas[idx])
+            .await
+            .expect("unable to get package")
+            .expect("missing package");
+    }
+
+    // aalib is the least recently used one
+    let cached = |dir: &Path| {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| !name.starts_with('.'))
+            .sorted()
+            .collect_vec()
+    };
+    assert_eq!(
+        cached(local_dir.path()),
+        vec![
+            format!("{}.tar.zst", metas[2].filename()),
+            format!("{}.tar.zst", metas[0].filename()),
+        ],
+        "cached files mismatch"
+    );
+
+    // cache is recognized after restart, so acl isn't downloaded again
+    std::fs::remove_file(
+        remote_dir
+            .path()
+            .join(format!("{}.tar.zst", metas[0].filename())),
+    )
+    .unwrap();
+    let mut pool = PackagePool::new(
+        FSStorage::new(remote_dir.path()),
+        local_dir.path().to_path_buf(),
+    )
+    .with_cache_limit(300_000);
+    pool.sync().await.expect("unable to sync");
+    assert_eq!(
+        pool.get(&metas[0]).await.expect("unable to get package"),
+        Some(
+            local_dir
+                .path()
+                .join(format!("{}.tar.zst", metas[0].filename()))
+        )
+    );
+    let index = CacheIndex::load(local_dir.path())
+        .await
+        .expect("unable to load cache index");
+    assert_eq!(index.len(), 2);
+    assert_eq!(index.size(), 139_672 + 39_197);
+
+    // a corrupt file in local cache is downloaded again
+    let cached_a52dec = local_dir
+        .path()
+        .join(format!("{}.tar.zst", metas[2].filename()));
+    std::fs::write(&cached_a52dec, b"truncated").unwrap();
+    pool.sync().await.expect("unable to sync");
+    pool.get(&metas[2])
+        .await
+        .expect("unable to get package")
+        .expect("missing package");
+    assert_eq!(
+        std::fs::read(&cached_a52dec).unwrap(),
+        std::fs::read("tests/pkgs/a52dec-0.7.4-11-x86_64.pkg.tar.zst").unwrap(),
+        "corrupt file kept"
+    );
+
+    // a pool which hasn't synced keeps existing cache entries
+    let mut pool = PackagePool::new(
+        FSStorage::new(remote_dir.path()),
+        local_dir.path().to_path_buf(),
+    );
+    pool.stage(LocalPackageUnit::new(
+        &PackageMeta::new("acl", &Version(String::from("2.3.1-2")), 0),
+        "tests/pkgs/acl-2.3.1-1-x86_64.pkg.tar.zst",
+    ));
+    pool.commit().await.expect("unable to commit");
+    let index = CacheIndex::load(local_dir.path())
+        .await
+        .expect("unable to load cache index");
+    assert_eq!(index.len(), 3, "cache index overwritten");
+}
+
+#[tokio::test]
+async fn must_hold_lease() {
+    let dir = tempdir().expect("unable to create temp dir");
+    let storage = FSStorage::new(dir.path());
+
+    let lease = Lease::acquire(&storage, "lease", "a", chrono::Duration::seconds(60))
+        .await
+        .expect("unable to acquire lease");
+    assert!(matches!(
+        Lease::acquire(&storage, "lease", "b", chrono::Duration::seconds(60)).await,
+        Err(StorageError::LeaseHeld(owner)) if owner == "a"
+    ));
+    lease
+        .release(&storage)
+        .await
+        .expect("unable to release lease");
+
+    // an expired lease can be taken over
+    let mut lease = Lease::acquire(&storage, "lease", "b", chrono::Duration::milliseconds(500))
+        .await
+        .expect("unable to acquire lease");
+    lease.renew(&storage).await.expect("unable to renew lease");
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    let other = Lease::acquire(&storage, "lease", "c", chrono::Duration::seconds(60))
+        .await
+        .expect("unable to acquire expired lease");
+    assert!(matches!(
+        lease.renew(&storage).await,
+        Err(StorageError::LeaseHeld(owner)) if owner == "c"
+    ));
+    assert_eq!(other.info().owner, "c");
+}
+
+#[tokio::test]
+async fn must_merge_concurrent_commits() {
+    let remote_dir = tempdir().expect("unable to create temp dir");
+    let acl = PackageMeta::new("acl", &Version(String::from("2.3.1-1")), 1);
+    let aalib = PackageMeta::new("aalib", &Version(String::from("1.4rc5-14")), 2);
+
+    // both pools see an empty repo
+    let local_dirs = [
+        tempdir().expect("unable to create temp dir"),
+        tempdir().expect("unable to create temp dir"),
+    ];
+    let mut pools = vec![];
+    for (owner, local_dir) in ["a", "b"].into_iter().zip(&local_dirs) {
+        pools.push(
+            PackagePool::open(
+                FSStorage::new(remote_dir.path()),
+                local_dir.path().to_path_buf(),
+            )
+            .await
+            .expect("unable to open pool")
+            .with_owner(owner),
+        );
+    }
+    pools[0].stage(LocalPackageUnit::new(
+        &acl,
+        "tests/pkgs/acl-2.3.1-1-x86_64.pkg.tar.zst",
+    ));
+    pools[0].commit().await.expect("unable to commit");
+    pools[1].stage(LocalPackageUnit::new(
+        &aalib,
+        "tests/pkgs/aalib-1.4rc5-14-x86_64.pkg.tar.zst",
+    ));
+    pools[1].commit().await.expect("unable to commit");
+
+    // the later commit keeps packages of the former one
+    let lock_file: LockFile =
+        serde_json::from_slice(&std::fs::read(remote_dir.path().join("index.lock")).unwrap())
+            .expect("unable to parse lock file");
+    assert_eq!(
+        lock_file
+            .packages
+            .into_iter()
+            .map(|unit| unit.meta)
+            .collect::<HashSet<_>>(),
+        HashSet::from([acl.clone(), aalib]),
+        "lock file mismatch"
+    );
+    assert!(!remote_dir.path().join("index.lease").exists());
+
+    // the same package with different content can't be merged
+    pools[1].stage(LocalPackageUnit::new(
+        &acl,
+        "tests/pkgs/a52dec-0.7.4-11-x86_64.pkg.tar.zst",
+    ));
+    assert!(matches!(
+        pools[1].commit().await,
+        Err(Error::StorageError(StorageError::Conflict))
+    ));
+
+    // lease held by others
+    Lease::acquire(
+        &FSStorage::new(remote_dir.path()),
+        "index.lease",
+        "c",
+        chrono::Duration::seconds(60),
+    )
+    .await
+    .expect("unable to acquire lease");
+    assert!(matches!(
+        pools[0].commit().await,
+        Err(Error::StorageError(StorageError::LeaseHeld(_)))
+    ));
+}
+
+#[tokio::test]
+async fn must_inject_faults() {
+    let storage = FaultyStorage::new(MemoryStorage::new())
+        .with_fault(
+            FaultRule::new(Operation::Get, Fault::Fail)
+                .key("a")
+                .skip(1)
+                .times(Some(1)),
+        )
+        .with_fault(FaultRule::new(Operation::Put, Fault::PartialWrite(2)).key("b"))
+        .with_fault(
+            FaultRule::new(Operation::Stat, Fault::Latency(Duration::from_millis(50))).key("a"),
+        );
+    storage
+        .put_file(Path::new("a"), setup_memory_bytestream())
+        .await
+        .expect("unable to put file");
+
+    // only the second get fails
+    let mut results = vec![];
+    for _ in 0..3 {
+        results.push(storage.get_file(Path::new("a")).await.is_ok());
+    }
+    assert_eq!(results, vec![true, false, true]);
+
+    // a partially written file is left behind
+    assert!(storage
+        .put_file(Path::new("b"), setup_memory_bytestream())
+        .await
+        .is_err());
+    assert_eq!(storage.inner().files()[Path::new("b")], vec![1, 2]);
+
+    let start = std::time::Instant::now();
+    storage.stat(Path::new("a")).await.expect("unable to stat");
+    assert!(start.elapsed() >= Duration::from_millis(50), "no latency");
+}
+
+#[tokio::test]
+async fn must_recover_interrupted_commit() {
+    let memory = Arc::new(MemoryStorage::new());
+    // lock file is partially written, and so is the retry during commit
+    let storage = FaultyStorage::new(memory.clone()).with_fault(
+        FaultRule::new(Operation::Put, Fault::PartialWrite(10))
+            .key("index.lock")
+            .times(Some(2)),
+    );
+    let local_dir = tempdir().expect("unable to create temp dir");
+    let mut pool = PackagePool::new(storage, local_dir.path().to_path_buf());
+    let acl = PackageMeta::new("acl", &Version(String::from("2.3.1-1")), 1);
+    pool.stage(LocalPackageUnit::new(
+        &acl,
+        "tests/pkgs/acl-2.3.1-1-x86_64.pkg.tar.zst",
+    ));
+    assert!(pool.commit().await.is_err(), "commit must fail");
+    assert!(memory.files().contains_key(Path::new("txn.journal")));
+
+    // the journal is replayed on next sync
+    pool.sync().await.expect("unable to sync");
+    let files = memory.files();
+    assert!(!files.contains_key(Path::new("txn.journal")));
+    let lock_file: LockFile =
+        serde_json::from_slice(&files[Path::new("index.lock")]).expect("unable to parse lock file");
+    assert_eq!(
+        lock_file
+            .packages
+            .into_iter()
+            .map(|unit| unit.meta)
+            .collect_vec(),
+        vec![acl],
+        "lock file mismatch"
+    );
+}
+
+#[tokio::test]
+async fn must_not_recover_live_commit() {
+    // the lock file is slow to write, so the journal stays around during the commit
+    let storage = Arc::new(
+        FaultyStorage::new(MemoryStorage::new()).with_fault(
+            FaultRule::new(Operation::Put, Fault::Latency(Duration::from_millis(500)))
+                .key("index.lock"),
+        ),
+    );
+    let committer_dir = tempdir().expect("unable to create temp dir");
+    let reader_dir = tempdir().expect("unable to create temp dir");
+    let mut committer =
+        PackagePool::new(storage.clone(), committer_dir.path().to_path_buf()).with_owner("a");
+    let mut reader =
+        PackagePool::new(storage.clone(), reader_dir.path().to_path_buf()).with_owner("b");
+    let acl = PackageMeta::new("acl", &Version(String::from("2.3.1-1")), 1);
+    committer.stage(LocalPackageUnit::new(
+        &acl,
+        "tests/pkgs/acl-2.3.1-1-x86_64.pkg.tar.zst",
+    ));
+
+    // syncing while the lease is held must leave the journal alone
+    let (committed, synced) = tokio::join!(committer.commit(), async {
+        while !storage
+            .inner()
+            .files()
+            .contains_key(Path::new("txn.journal"))
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        reader.sync().await
+    });
+    committed.expect("unable to commit");
+    synced.expect("unable to sync");
+    let files = storage.inner().files();
+    assert!(!files.contains_key(Path::new("txn.journal")));
+    assert!(!files.contains_key(Path::new("index.lease")));
+
+    reader.sync().await.expect("unable to sync");
+    assert!(
+        reader
+            .get(&acl)
+            .await
+            .expect("unable to get package")
+            .is_some(),
+        "package missing"
+    );
+}
+
+#[tokio::test]
+async fn must_mirror_repo() {
+    let source = Arc::new(MemoryStorage::new());
+    let dest = Arc::new(MemoryStorage::new());
+    let local_dir = tempdir().expect("unable to create temp dir");
+    let mut pool =
+        PackagePool::new(source.clone(), local_dir.path().to_path_buf()).with_repo("test");
+
+    let pkgs = [
+        ("acl", "2.3.1-1"),
+        ("aalib", "1.4rc5-14"),
+        ("a52dec", "0.7.4-11"),
+    ];
+    for (name, version) in &pkgs[..2] {
+        pool.stage(LocalPackageUnit::new(
+            PackageMeta::new(name, &Version(version.to_string()), 0),
+            format!("tests/pkgs/{}-{}-x86_64.pkg.tar.zst", name, version),
+        ));
+    }
+    pool.commit().await.expect("unable to commit");
+
+    let report = mirror(&source, &dest).await.expect("unable to mirror");
+    assert_eq!((report.copied.len(), report.skipped), (2, 0));
+    assert_eq!(
+        report.databases.into_iter().sorted().collect_vec(),
+        vec![
+            PathBuf::from("test.db"),
+            PathBuf::from("test.db.tar.zst"),
+            PathBuf::from("test.files"),
+            PathBuf::from("test.files.tar.zst"),
+        ]
+    );
+
+    // only new packages are copied
+    let (name, version) = pkgs[2];
+    pool.stage(LocalPackageUnit::new(
+        PackageMeta::new(name, &Version(version.to_string()), 0),
+        format!("tests/pkgs/{}-{}-x86_64.pkg.tar.zst", name, version),
+    ));
+    pool.commit().await.expect("unable to commit");
+    // databases gone from source are removed
+    dest.put_file(Path::new("old.db.tar.xz"), ByteStream::from(vec![0]))
+        .await
+        .expect("unable to put file");
+    let report = mirror(&source, &dest).await.expect("unable to mirror");
+    assert_eq!((report.copied.len(), report.skipped), (1, 2));
+    assert_eq!(report.removed, vec![PathBuf::from("old.db.tar.xz")]);
+
+    // dest is in step with source
+    let files = dest.files();
+    assert_eq!(
+        files[Path::new("index.lock")],
+        source.files()[Path::new("index.lock")]
+    );
+    let db = PacmanDB::from_reader(&*files[Path::new("test.db")]).expect("unable to read db");
+    assert_eq!(db.len(), 3);
+    assert!(verify_repo(&dest, Path::new("test.db"))
+        .await
+        .expect("unable to verify")
+        .is_ok());
+
+    assert!(!files.contains_key(Path::new("old.db.tar.xz")));
+
+    let report = mirror(&source, &dest).await.expect("unable to mirror");
+    assert_eq!((report.copied.len(), report.skipped), (0, 3));
+
+    // source is being committed to
+    Lease::acquire(&source, "index.lease", "c", chrono::Duration::seconds(60))
+        .await
+        .expect("unable to acquire lease");
+    assert!(matches!(
+        mirror(&source, &dest).await,
+        Err(StorageError::LeaseHeld(_))
+    ));
+    assert!(!dest.files().contains_key(Path::new("index.lease")));
+}
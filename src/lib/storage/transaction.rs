@@ -1,9 +1,11 @@
 use std::collections::VecDeque;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 use tokio::io::AsyncReadExt;
 
+use crate::consts::JOURNAL_INLINE_LIMIT;
 use crate::error::StorageError;
 
 use super::types::*;
@@ -15,6 +17,8 @@ use super::StorageProvider;
 // especially when the file is large.
 pub enum TxnAction {
     Put(PathBuf, ByteStream),
+    // put, overwriting the existing file if any
+    Replace(PathBuf, ByteStream),
     Delete(PathBuf),
     Assertion(PathBuf, Box<dyn Fn(Option<Vec<u8>>) -> Result<()> + Send>),
     Barrier,
@@ -24,6 +28,7 @@ impl TxnAction {
     pub async fn execute<T: StorageProvider>(self, target: &T) -> Result<()> {
         match self {
             TxnAction::Put(key, data) => target.put_file(&key, data).await?,
+            TxnAction::Replace(key, data) => target.replace_file(&key, data).await?,
             TxnAction::Delete(key) => target.delete_file(&key).await?,
             TxnAction::Barrier => panic!("barrier can't be executed"),
             TxnAction::Assertion(key, func) => {
@@ -48,48 +53,348 @@ impl TxnAction {
     }
 }
 
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum JournalAction {
+    // small in-memory data is inlined (base64) so that the action can be replayed
+    Put {
+        key: PathBuf,
+        data: Option<String>,
+    },
+    // the old file is copied to `backup` before the transaction if it may be rolled back,
+    // a missing backup means there was no old file
+    Replace {
+        key: PathBuf,
+        data: Option<String>,
+        #[serde(default)]
+        backup: Option<PathBuf>,
+    },
+    Delete {
+        key: PathBuf,
+    },
+}
+
+// Written to storage before a transaction is executed.
+// A marker `{journal}.{n}` is put after the n-th stage is done.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Journal {
+    pub stages: Vec<Vec<JournalAction>>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Recovery {
+    Clean,
+    RolledBack,
+    RolledForward,
+}
+
+fn marker_key(journal: &Path, stage: usize) -> PathBuf {
+    let mut key = journal.as_os_str().to_os_string();
+    key.push(format!(".{}", stage));
+    PathBuf::from(key)
+}
+
+fn backup_key(journal: &Path, stage: usize, idx: usize) -> PathBuf {
+    let mut key = journal.as_os_str().to_os_string();
+    key.push(format!(".bak.{}.{}", stage, idx));
+    PathBuf::from(key)
+}
+
+pub(crate) async fn delete_if_exists<T: StorageProvider>(target: &T, key: &Path) -> Result<()> {
+    match target.delete_file(key).await {
+        Err(StorageError::FileNotExists(_)) => Ok(()),
+        result => result,
+    }
+}
+
+// small in-memory data to be inlined into the journal
+fn inline(data: &ByteStream) -> Option<String> {
+    data.bytes()
+        .filter(|v| v.len() as u64 <= JOURNAL_INLINE_LIMIT)
+        .map(base64::encode)
+}
+
+impl Journal {
+    fn new(key: &Path, stages: &[Vec<TxnAction>]) -> Self {
+        let mut stages: Vec<Vec<_>> = stages
+            .iter()
+            .map(|stage| {
+                stage
+                    .iter()
+                    .filter_map(|action| match action {
+                        TxnAction::Put(key, data) => Some(JournalAction::Put {
+                            key: key.clone(),
+                            data: inline(data),
+                        }),
+                        TxnAction::Replace(key, data) => Some(JournalAction::Replace {
+                            key: key.clone(),
+                            data: inline(data),
+                            backup: None,
+                        }),
+                        TxnAction::Delete(key) => Some(JournalAction::Delete { key: key.clone() }),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .collect();
+
+        // a stage may be rolled back if it or any later stage can't be replayed
+        let mut replayable = true;
+        for (stage_idx, stage) in stages.iter_mut().enumerate().rev() {
+            replayable &= stage.iter().all(JournalAction::is_replayable);
+            if replayable {
+                continue;
+            }
+            for (idx, action) in stage.iter_mut().enumerate() {
+                if let JournalAction::Replace { backup, .. } = action {
+                    *backup = Some(backup_key(key, stage_idx, idx));
+                }
+            }
+        }
+        Self { stages }
+    }
+
+    fn backups(&self) -> impl Iterator<Item = (&PathBuf, &PathBuf)> {
+        self.stages
+            .iter()
+            .flatten()
+            .filter_map(|action| match action {
+                JournalAction::Replace {
+                    key,
+                    backup: Some(backup),
+                    ..
+                } => Some((key, backup)),
+                _ => None,
+            })
+    }
+
+    // copy files to be replaced to their backups, must be done before the journal is put
+    async fn backup<T: StorageProvider>(&self, target: &T) -> Result<()> {
+        for (key, backup) in self.backups() {
+            match target.get_file(key).await {
+                Ok(data) => target.replace_file(backup, data).await?,
+                // leftover of an earlier transaction which didn't get to put its journal
+                Err(StorageError::FileNotExists(_)) => delete_if_exists(target, backup).await?,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn load<T: StorageProvider>(target: &T, key: &Path) -> Result<Option<Self>> {
+        match target.get_file(key).await {
+            Ok(mut stream) => {
+                let mut buf = vec![];
+                stream.read_to_end(&mut buf).await?;
+                Ok(Some(serde_json::from_slice(&buf)?))
+            }
+            Err(StorageError::FileNotExists(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // number of finished stages
+    async fn progress<T: StorageProvider>(&self, target: &T, key: &Path) -> Result<usize> {
+        let mut done = 0;
+        for stage in 0..self.stages.len() {
+            match target.stat(&marker_key(key, stage)).await {
+                Ok(_) => done = stage + 1,
+                Err(StorageError::FileNotExists(_)) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(done)
+    }
+
+    async fn cleanup<T: StorageProvider>(&self, target: &T, key: &Path) -> Result<()> {
+        for stage in 0..self.stages.len() {
+            delete_if_exists(target, &marker_key(key, stage)).await?;
+        }
+        // backups are dropped last, the journal may still need them until it's deleted
+        delete_if_exists(target, key).await?;
+        for (_, backup) in self.backups() {
+            delete_if_exists(target, backup).await?;
+        }
+        Ok(())
+    }
+
+    // Undo puts and replaces of finished and interrupted stages.
+    // NOTE deleted files can't be restored.
+    async fn rollback<T: StorageProvider>(&self, target: &T, done: usize) -> Result<()> {
+        for stage in self.stages.iter().take(done + 1).rev() {
+            for action in stage {
+                match action {
+                    JournalAction::Put { key, .. } => delete_if_exists(target, key).await?,
+                    JournalAction::Replace {
+                        key,
+                        backup: Some(backup),
+                        ..
+                    } => match target.get_file(backup).await {
+                        Ok(data) => target.replace_file(key, data).await?,
+                        Err(StorageError::FileNotExists(_)) => {
+                            delete_if_exists(target, key).await?
+                        }
+                        Err(e) => return Err(e),
+                    },
+                    _ => (),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Replay interrupted and remaining stages, only possible if all their puts are inlined.
+    async fn roll_forward<T: StorageProvider>(&self, target: &T, done: usize) -> Result<()> {
+        for stage in self.stages.iter().skip(done) {
+            for action in stage {
+                match action {
+                    // the file may have been written already
+                    JournalAction::Put { key, data } | JournalAction::Replace { key, data, .. } => {
+                        let data = base64::decode(data.as_ref().unwrap())
+                            .map_err(|_| StorageError::Conflict)?;
+                        target.replace_file(key, data.into()).await?;
+                    }
+                    JournalAction::Delete { key } => delete_if_exists(target, key).await?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn can_roll_forward(&self, done: usize) -> bool {
+        self.stages
+            .iter()
+            .skip(done)
+            .flatten()
+            .all(JournalAction::is_replayable)
+    }
+}
+
+impl JournalAction {
+    // whether the action can be replayed from the journal alone
+    const fn is_replayable(&self) -> bool {
+        !matches!(
+            self,
+            JournalAction::Put { data: None, .. } | JournalAction::Replace { data: None, .. }
+        )
+    }
+}
+
 // NOTE
-// There's no rollback support now.
-// Also, atomicity can't be ensured because S3 doesn't support atomic move operation.
+// Atomicity can't be ensured because S3 doesn't support atomic move operation.
+// If a journal is set, an interrupted transaction can be rolled back or forward by `Txn::recover`.
 #[derive(Default)]
 pub struct Txn {
     seq: VecDeque<TxnAction>,
+    journal: Option<PathBuf>,
 }
 
 impl Txn {
     pub fn new() -> Self {
         Default::default()
     }
+    pub fn with_journal(journal: impl AsRef<Path>) -> Self {
+        Self {
+            seq: VecDeque::new(),
+            journal: Some(journal.as_ref().to_path_buf()),
+        }
+    }
     pub fn add(&mut self, action: TxnAction) {
         self.seq.push_back(action);
     }
-    async fn join_commit<T: StorageProvider>(
-        staging: &mut Vec<TxnAction>,
-        target: &T,
-    ) -> Result<()> {
-        let staging_futures = staging
-            .drain(..)
-            .map(|act: TxnAction| act.execute(target))
-            .collect_vec();
-        futures::future::try_join_all(staging_futures).await?;
-        staging.clear();
-        Ok(())
-    }
-    pub async fn commit<T: StorageProvider>(mut self, target: &T) -> Result<()> {
+
+    // split actions into stages, assertions are executed in their own stages
+    fn stages(self) -> Vec<Vec<TxnAction>> {
+        let mut stages = vec![];
         let mut staging = vec![];
-        while let Some(action) = self.seq.pop_front() {
+        for action in self.seq {
             match action {
                 TxnAction::Assertion(_, _) => {
-                    Self::join_commit(&mut staging, target).await?;
-                    action.execute(target).await?;
+                    if !staging.is_empty() {
+                        stages.push(std::mem::take(&mut staging));
+                    }
+                    stages.push(vec![action]);
+                }
+                TxnAction::Barrier => {
+                    if !staging.is_empty() {
+                        stages.push(std::mem::take(&mut staging));
+                    }
                 }
-                TxnAction::Barrier => Self::join_commit(&mut staging, target).await?,
                 _ => staging.push(action),
             }
         }
         if !staging.is_empty() {
-            Self::join_commit(&mut staging, target).await?;
+            stages.push(staging);
         }
+        stages
+    }
+
+    async fn join_commit<T: StorageProvider>(stage: Vec<TxnAction>, target: &T) -> Result<()> {
+        let staging_futures = stage
+            .into_iter()
+            .map(|act: TxnAction| act.execute(target))
+            .collect_vec();
+        futures::future::try_join_all(staging_futures).await?;
         Ok(())
     }
+
+    pub async fn commit<T: StorageProvider>(mut self, target: &T) -> Result<()> {
+        let journal_key = self.journal.take();
+        let stages = self.stages();
+
+        let journal_key = match journal_key {
+            Some(journal_key) => journal_key,
+            None => {
+                for stage in stages {
+                    Self::join_commit(stage, target).await?;
+                }
+                return Ok(());
+            }
+        };
+
+        // an existing journal means an unrecovered transaction
+        if Journal::load(target, &journal_key).await?.is_some() {
+            return Err(StorageError::Conflict);
+        }
+        let journal = Journal::new(&journal_key, &stages);
+        journal.backup(target).await?;
+        target
+            .put_file(&journal_key, serde_json::to_vec(&journal)?.into())
+            .await?;
+
+        for (idx, stage) in stages.into_iter().enumerate() {
+            let result = match Self::join_commit(stage, target).await {
+                Ok(_) => {
+                    target
+                        .put_file(&marker_key(&journal_key, idx), vec![].into())
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                // try to recover now, or the journal is left for the next start
+                drop(Self::recover(target, &journal_key).await);
+                return Err(e);
+            }
+        }
+
+        journal.cleanup(target, &journal_key).await
+    }
+
+    // recover an interrupted transaction from its journal
+    pub async fn recover<T: StorageProvider>(target: &T, journal_key: &Path) -> Result<Recovery> {
+        let journal = match Journal::load(target, journal_key).await? {
+            Some(journal) => journal,
+            None => return Ok(Recovery::Clean),
+        };
+        let done = journal.progress(target, journal_key).await?;
+        let recovery = if journal.can_roll_forward(done) {
+            journal.roll_forward(target, done).await?;
+            Recovery::RolledForward
+        } else {
+            journal.rollback(target, done).await?;
+            Recovery::RolledBack
+        };
+        journal.cleanup(target, journal_key).await?;
+        Ok(recovery)
+    }
 }
@@ -1,103 +1,223 @@
 use std::convert::TryFrom;
+use std::fmt::Debug;
 use std::fs::File;
+use std::future::Future;
 use std::io::Result as IOResult;
-use std::io::{Cursor, SeekFrom};
+use std::io::{Error, ErrorKind, Seek, SeekFrom};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
 
-use bytes::Bytes;
+use async_trait::async_trait;
+use bytes::{Buf, Bytes};
 use futures::{ready, Stream};
 use tempfile::NamedTempFile;
-use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt, AsyncWriteExt, ReadBuf};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt, ReadBuf};
+use tokio::task::JoinHandle;
 
+use crate::consts::BYTESTREAM_CHUNK_SIZE;
 use crate::utils::is_same_fs;
 
 #[derive(Debug, Clone)]
 pub enum FileObject {
     Unnamed,
-    Path(PathBuf), // it's assumed that the file exists when object is alive
+    Path(PathBuf), // content is read from the handle opened on creation
     NamedTemp(Arc<NamedTempFile>),
 }
 
-#[derive(Debug)]
-pub enum ByteStream {
-    Memory(Cursor<Vec<u8>>),
+// Data fetched by byte range on demand, e.g. an object in remote storage.
+#[async_trait]
+pub trait RangeSource: Debug + Send + Sync {
+    async fn get_range(&self, range: Range<u64>) -> IOResult<Bytes>;
+}
+
+#[derive(Debug, Clone)]
+enum Backing {
+    Memory(Bytes),
+    // Files are read by offset, so clones of the handle don't interfere with each other.
     File {
-        handle: tokio::fs::File,
+        handle: Arc<File>,
         object_type: FileObject,
-        length: u64,
     },
+    Remote(Arc<dyn RangeSource>),
+}
+
+// A cheaply cloneable stream over a window of bytes in memory or in a file.
+#[derive(Debug)]
+pub struct ByteStream {
+    backing: Backing,
+    range: Range<u64>, // window of the backing data
+    pos: u64,          // absolute position of the reader
+    chunk_size: usize,
+    buffered: Bytes,                              // data at `pos` not yet consumed
+    pending: Option<JoinHandle<IOResult<Bytes>>>, // in-flight file read or remote fetch
+}
+
+// read up to `len` bytes at `offset`, less only if eof is reached
+fn read_at(file: &File, offset: u64, len: usize) -> IOResult<Bytes> {
+    let mut buf = vec![0; len];
+    let mut filled = 0;
+    while filled < len {
+        match file.read_at(&mut buf[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    buf.truncate(filled);
+    Ok(Bytes::from(buf))
 }
 
 impl ByteStream {
-    pub fn from_path(path: impl AsRef<Path>) -> IOResult<Self> {
-        let handle = std::fs::File::open(path.as_ref())?;
+    fn new(backing: Backing, length: u64) -> Self {
+        Self {
+            backing,
+            range: 0..length,
+            pos: 0,
+            chunk_size: BYTESTREAM_CHUNK_SIZE,
+            buffered: Bytes::new(),
+            pending: None,
+        }
+    }
+
+    fn from_file(handle: File, object_type: FileObject) -> IOResult<Self> {
         let length = handle.metadata()?.len();
-        Ok(Self::File {
-            handle: tokio::fs::File::from_std(handle),
-            object_type: FileObject::Path(path.as_ref().to_path_buf()),
+        Ok(Self::new(
+            Backing::File {
+                handle: Arc::new(handle),
+                object_type,
+            },
             length,
-        })
+        ))
+    }
+
+    // only a chunk at a time is fetched from `source`, which holds `length` bytes
+    pub fn from_source(source: Arc<dyn RangeSource>, length: u64) -> Self {
+        Self::new(Backing::Remote(source), length)
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> IOResult<Self> {
+        let handle = File::open(path.as_ref())?;
+        Self::from_file(handle, FileObject::Path(path.as_ref().to_path_buf()))
+    }
+
+    // size of chunks yielded by `Stream` and read from files at once
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    pub const fn chunk_size(&self) -> usize {
+        self.chunk_size
     }
 
     pub const fn in_memory(&self) -> bool {
-        matches!(self, ByteStream::Memory(_))
+        matches!(self.backing, Backing::Memory(_))
     }
 
-    pub fn size(&self) -> u64 {
-        match self {
-            ByteStream::Memory(v) => v.get_ref().len() as u64,
-            ByteStream::File { length, .. } => *length,
-        }
+    pub const fn size(&self) -> u64 {
+        self.range.end - self.range.start
     }
 
-    pub async fn into_file(self, path: impl AsRef<Path> + Clone + Send) -> IOResult<()> {
-        use tokio::fs::File;
-        match self {
-            ByteStream::Memory(v) => {
-                let data = v.into_inner();
-                let mut dest = File::create(path).await?;
-                dest.write_all(&data).await?;
-                dest.sync_all().await?;
+    // whole content if the stream is in memory, no copy is made
+    pub fn bytes(&self) -> Option<Bytes> {
+        match &self.backing {
+            Backing::Memory(data) => {
+                Some(data.slice(self.range.start as usize..self.range.end as usize))
             }
-            ByteStream::File {
-                object_type: FileObject::NamedTemp(file),
-                ..
-            } => {
-                if is_same_fs(file.path(), path.clone()) {
-                    match Arc::try_unwrap(file) {
-                        Ok(file) => {
-                            // this stream is the only owner of the file, persist
-                            file.persist(path)?;
-                        }
-                        Err(file) => {
-                            // this stream isn't the only owner, copy file
-                            tokio::fs::copy(file.path(), path).await?;
-                        }
-                    }
-                } else {
-                    // we can't persist tempfile across filesystems
-                    tokio::fs::copy(file.path(), path).await?;
+            Backing::File { .. } | Backing::Remote(_) => None,
+        }
+    }
+
+    // A sub-stream over `range` of this stream, sharing the backing data.
+    // The range is clamped to the size of this stream.
+    pub fn slice(&self, range: Range<u64>) -> Self {
+        let start = self
+            .range
+            .start
+            .saturating_add(range.start)
+            .min(self.range.end);
+        let end = self
+            .range
+            .start
+            .saturating_add(range.end)
+            .min(self.range.end)
+            .max(start);
+        Self {
+            backing: self.backing.clone(),
+            range: start..end,
+            pos: start,
+            chunk_size: self.chunk_size,
+            buffered: Bytes::new(),
+            pending: None,
+        }
+    }
+
+    pub async fn into_file(mut self, path: impl AsRef<Path> + Clone + Send) -> IOResult<()> {
+        if let Backing::File {
+            object_type: FileObject::NamedTemp(file),
+            ..
+        } = &self.backing
+        {
+            // this stream is the only owner of the whole file, persist
+            // we can't persist tempfile across filesystems
+            let whole = self.range == (0..file.as_file().metadata()?.len());
+            if whole && Arc::strong_count(file) == 1 && is_same_fs(file.path(), path.clone()) {
+                if let Backing::File {
+                    object_type: FileObject::NamedTemp(file),
+                    ..
+                } = self.backing
+                {
+                    Arc::try_unwrap(file).unwrap().persist(path)?;
+                    return Ok(());
                 }
             }
-            ByteStream::File {
-                object_type: FileObject::Path(src_path),
-                ..
-            } => {
-                tokio::fs::copy(src_path, path).await?;
+        }
+
+        // otherwise copy the content
+        self.seek(SeekFrom::Start(0)).await?;
+        let mut dest = tokio::fs::File::create(path).await?;
+        tokio::io::copy(&mut self, &mut dest).await?;
+        dest.sync_all().await?;
+        Ok(())
+    }
+
+    // make sure the next chunk is buffered, unless eof is reached
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<IOResult<()>> {
+        if !self.buffered.is_empty() || self.pos >= self.range.end {
+            return Poll::Ready(Ok(()));
+        }
+        let offset = self.pos;
+        let len = (self.range.end - offset).min(self.chunk_size as u64) as usize;
+        match &self.backing {
+            Backing::Memory(data) => {
+                self.buffered = data.slice(offset as usize..offset as usize + len);
+            }
+            Backing::File { handle, .. } => {
+                let pending = self.pending.get_or_insert_with(|| {
+                    let handle = handle.clone();
+                    tokio::task::spawn_blocking(move || read_at(&handle, offset, len))
+                });
+                let result = ready!(Pin::new(pending).poll(cx));
+                self.pending = None;
+                // an empty read means the file is truncated, treated as eof
+                self.buffered = result.map_err(|e| Error::new(ErrorKind::Other, e))??;
             }
-            ByteStream::File {
-                handle: mut file, ..
-            } => {
-                file.seek(SeekFrom::Start(0)).await?;
-                let mut dest = File::create(path).await?;
-                tokio::io::copy(&mut file, &mut dest).await?;
-                dest.sync_all().await?;
+            Backing::Remote(source) => {
+                let pending = self.pending.get_or_insert_with(|| {
+                    let source = source.clone();
+                    tokio::spawn(async move { source.get_range(offset..offset + len as u64).await })
+                });
+                let result = ready!(Pin::new(pending).poll(cx));
+                self.pending = None;
+                self.buffered = result.map_err(|e| Error::new(ErrorKind::Other, e))??;
             }
         }
-        Ok(())
+        Poll::Ready(Ok(()))
     }
 }
 
@@ -105,42 +225,7 @@ impl Clone for ByteStream {
     // NOTE
     // the cloned bytestream will have its pointer rewound
     fn clone(&self) -> Self {
-        match self {
-            ByteStream::Memory(v) => Self::Memory(Cursor::new(v.clone().into_inner())), // TODO use custom cursor to avoid this clone
-            ByteStream::File {
-                object_type: FileObject::NamedTemp(temp_file),
-                length,
-                ..
-            } => Self::File {
-                handle: tokio::fs::File::from_std(temp_file.reopen().unwrap()),
-                object_type: FileObject::NamedTemp(temp_file.clone()),
-                length: *length,
-            },
-            ByteStream::File {
-                object_type: FileObject::Path(file_path),
-                ..
-            } => {
-                let mut src = std::fs::File::open(file_path).unwrap();
-                let mut new_file = NamedTempFile::new().unwrap();
-                std::io::copy(&mut src, &mut new_file).unwrap();
-                Self::try_from(new_file).unwrap()
-            }
-            ByteStream::File {
-                object_type: FileObject::Unnamed,
-                ..
-            } => {
-                // NOTE
-                // It's possible to support cloning unnamed file backed bytestream by
-                // 1. create another handle on the same fd
-                // 2. record its current pos (by seek(current))
-                // 3. rewind it
-                // 4. copy it to another temp file
-                // 5. create the new stream on the newly created temp file
-                // 6. seek the original file to the previously saved pos
-                // However, it's unsafe and won't sync well.
-                panic!("unsupported")
-            }
-        }
+        self.slice(0..self.size())
     }
 }
 
@@ -148,44 +233,50 @@ impl Stream for ByteStream {
     type Item = IOResult<Bytes>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let mut inner_buf = [0; 8192];
-        let mut buf = ReadBuf::new(&mut inner_buf);
-        match ready!(self.poll_read(cx, &mut buf)) {
-            Ok(_) => Some(Ok(Bytes::from(Vec::from(buf.filled())))).into(),
-            Err(e) => Some(Err(e)).into(),
+        let this = self.get_mut();
+        if let Err(e) = ready!(this.poll_fill(cx)) {
+            return Poll::Ready(Some(Err(e)));
         }
+        if this.buffered.is_empty() {
+            return Poll::Ready(None);
+        }
+        let chunk = std::mem::take(&mut this.buffered);
+        this.pos += chunk.len() as u64;
+        Poll::Ready(Some(Ok(chunk)))
+    }
+}
+
+impl From<Bytes> for ByteStream {
+    fn from(data: Bytes) -> Self {
+        let length = data.len() as u64;
+        Self::new(Backing::Memory(data), length)
     }
 }
 
 impl From<Vec<u8>> for ByteStream {
     fn from(v: Vec<u8>) -> Self {
-        Self::Memory(Cursor::new(v))
+        Self::from(Bytes::from(v))
     }
 }
 
+// NOTE
+// The stream covers the whole file, it's read through a new handle.
 impl TryFrom<NamedTempFile> for ByteStream {
     type Error = std::io::Error;
 
     fn try_from(f: NamedTempFile) -> Result<Self, Self::Error> {
-        let length = f.as_file().metadata()?.len();
-        Ok(Self::File {
-            handle: f.reopen()?.into(),
-            object_type: FileObject::NamedTemp(Arc::new(f)),
-            length,
-        })
+        let handle = f.reopen()?;
+        Self::from_file(handle, FileObject::NamedTemp(Arc::new(f)))
     }
 }
 
+// The stream starts at the current position of the file, like reading from the handle.
 impl TryFrom<std::fs::File> for ByteStream {
     type Error = std::io::Error;
 
-    fn try_from(f: File) -> Result<Self, Self::Error> {
-        let length = f.metadata()?.len();
-        Ok(Self::File {
-            handle: f.into(),
-            object_type: FileObject::Unnamed,
-            length,
-        })
+    fn try_from(mut f: File) -> Result<Self, Self::Error> {
+        let start = f.stream_position()?;
+        Ok(Self::from_file(f, FileObject::Unnamed)?.slice(start..u64::MAX))
     }
 }
 
@@ -195,25 +286,38 @@ impl AsyncRead for ByteStream {
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<IOResult<()>> {
-        match self.get_mut() {
-            ByteStream::Memory(v) => Pin::new(v).poll_read(cx, buf),
-            ByteStream::File { handle: f, .. } => Pin::new(f).poll_read(cx, buf),
-        }
+        let this = self.get_mut();
+        ready!(this.poll_fill(cx))?;
+        let len = this.buffered.len().min(buf.remaining());
+        buf.put_slice(&this.buffered[..len]);
+        this.buffered.advance(len);
+        this.pos += len as u64;
+        Poll::Ready(Ok(()))
     }
 }
 
 impl AsyncSeek for ByteStream {
     fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> IOResult<()> {
-        match self.get_mut() {
-            ByteStream::Memory(v) => Pin::new(v).start_seek(position),
-            ByteStream::File { handle: f, .. } => Pin::new(f).start_seek(position),
-        }
+        let this = self.get_mut();
+        let current = i128::from(this.pos - this.range.start);
+        let target = match position {
+            SeekFrom::Start(offset) => i128::from(offset),
+            SeekFrom::End(offset) => i128::from(this.size()) + i128::from(offset),
+            SeekFrom::Current(offset) => current + i128::from(offset),
+        };
+        let target = u64::try_from(target)
+            .ok()
+            .and_then(|target| this.range.start.checked_add(target))
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "invalid seek position"))?;
+
+        // in-flight reads are positioned, so they can be dropped safely
+        this.pos = target;
+        this.buffered = Bytes::new();
+        this.pending = None;
+        Ok(())
     }
 
-    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IOResult<u64>> {
-        match self.get_mut() {
-            ByteStream::Memory(v) => Pin::new(v).poll_complete(cx),
-            ByteStream::File { handle: f, .. } => Pin::new(f).poll_complete(cx),
-        }
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<IOResult<u64>> {
+        Poll::Ready(Ok(self.pos - self.range.start))
     }
 }
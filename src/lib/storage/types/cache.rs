@@ -0,0 +1,141 @@
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use super::Result;
+
+pub const CACHE_INDEX: &str = ".cache.index";
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub size: u64,
+    pub last_access: u64, // logical clock, larger is newer
+}
+
+// Persistent index of local cache, keyed by filename relative to cache dir.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CacheIndex {
+    clock: u64,
+    entries: HashMap<PathBuf, CacheEntry>,
+}
+
+impl CacheIndex {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // load index saved in cache dir, and reconcile it with files on disk
+    // a corrupt index is rebuilt from scratch
+    pub async fn load(dir: &Path) -> Result<Self> {
+        let mut index: Self = match tokio::fs::read(dir.join(CACHE_INDEX)).await {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_default(),
+            Err(e) if e.kind() == ErrorKind::NotFound => Self::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut files = vec![];
+        let mut dir_entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = dir_entries.next_entry().await? {
+            let meta = entry.metadata().await?;
+            // skip index and unfinished downloads (temp files)
+            if !meta.is_file() || entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            files.push((
+                PathBuf::from(entry.file_name()),
+                meta.len(),
+                meta.modified()?,
+            ));
+        }
+
+        let present: HashSet<_> = files.iter().map(|(key, _, _)| key.clone()).collect();
+        index.entries.retain(|key, _| present.contains(key));
+        // files unknown to the index are ordered by their mtime
+        for (key, size, _) in files.into_iter().sorted_by_key(|(_, _, mtime)| *mtime) {
+            match index.entries.get_mut(&key) {
+                Some(entry) => entry.size = size,
+                None => index.insert(key, size),
+            }
+        }
+        Ok(index)
+    }
+
+    pub async fn save(&self, dir: &Path) -> Result<()> {
+        // write to a temp file first so that the index is never half-written
+        let path = dir.join(CACHE_INDEX);
+        let mut temp_path = path.as_os_str().to_os_string();
+        temp_path.push(".tmp");
+        tokio::fs::write(&temp_path, serde_json::to_vec(self)?).await?;
+        tokio::fs::rename(&temp_path, &path).await?;
+        Ok(())
+    }
+
+    pub fn insert(&mut self, key: impl AsRef<Path>, size: u64) {
+        self.clock += 1;
+        self.entries.insert(
+            key.as_ref().to_path_buf(),
+            CacheEntry {
+                size,
+                last_access: self.clock,
+            },
+        );
+    }
+
+    pub fn touch(&mut self, key: impl AsRef<Path>) {
+        if let Some(entry) = self.entries.get_mut(key.as_ref()) {
+            self.clock += 1;
+            entry.last_access = self.clock;
+        }
+    }
+
+    pub fn remove(&mut self, key: impl AsRef<Path>) -> Option<CacheEntry> {
+        self.entries.remove(key.as_ref())
+    }
+
+    pub fn get(&self, key: impl AsRef<Path>) -> Option<&CacheEntry> {
+        self.entries.get(key.as_ref())
+    }
+
+    pub fn contains(&self, key: impl AsRef<Path>) -> bool {
+        self.entries.contains_key(key.as_ref())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // total size of cached files
+    pub fn size(&self) -> u64 {
+        self.entries.values().map(|entry| entry.size).sum()
+    }
+
+    // drop least recently used entries until total size fits in limit, `keep` is never dropped
+    // returns keys of dropped entries, files aren't touched
+    pub fn evict(&mut self, limit: u64, keep: Option<&Path>) -> Vec<PathBuf> {
+        let lru = self
+            .entries
+            .iter()
+            .filter(|(key, _)| Some(key.as_path()) != keep)
+            .sorted_by_key(|(_, entry)| entry.last_access)
+            .map(|(key, _)| key.clone())
+            .collect_vec();
+
+        let mut total = self.size();
+        let mut evicted = vec![];
+        for key in lru {
+            if total <= limit {
+                break;
+            }
+            total -= self.entries.remove(&key).unwrap().size;
+            evicted.push(key);
+        }
+        evicted
+    }
+}
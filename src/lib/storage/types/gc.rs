@@ -0,0 +1,26 @@
+use std::path::PathBuf;
+
+use chrono::Duration;
+
+use super::PackageMeta;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RetentionPolicy {
+    KeepAll,
+    // keep the last N versions of each package
+    KeepLast(usize),
+    // keep versions uploaded within given duration
+    KeepFor(Duration),
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self::KeepAll
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct GcReport {
+    pub expired: Vec<PackageMeta>, // packages dropped from lock file
+    pub deleted: Vec<PathBuf>,     // files deleted (or to be deleted in dry-run)
+}
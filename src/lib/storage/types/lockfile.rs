@@ -28,6 +28,19 @@ impl LockFile {
     pub fn new() -> Self {
         Default::default()
     }
+
+    // attach sha256 of package files
+    pub(crate) fn with_digests(mut self, digests: &DigestMap) -> Self {
+        self.packages = self
+            .packages
+            .into_iter()
+            .map(|unit| RemotePackageUnit {
+                sha256: digests.get(&unit.key).cloned(),
+                ..unit
+            })
+            .collect();
+        self
+    }
 }
 
 impl From<&MetaKeyMap> for LockFile {
@@ -40,6 +53,7 @@ impl From<&MetaKeyMap> for LockFile {
                 .map(|(meta, key)| RemotePackageUnit {
                     meta: meta.clone(),
                     key: key.clone(),
+                    sha256: None,
                 })
                 .collect(),
         }
@@ -54,3 +68,12 @@ impl From<&LockFile> for MetaKeyMap {
             .collect()
     }
 }
+
+impl From<&LockFile> for DigestMap {
+    fn from(l: &LockFile) -> Self {
+        l.packages
+            .iter()
+            .filter_map(|unit| Some((unit.key.clone(), unit.sha256.clone()?)))
+            .collect()
+    }
+}
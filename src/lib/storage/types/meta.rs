@@ -0,0 +1,11 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct FileMeta {
+    pub path: PathBuf, // relative to storage base
+    pub size: u64,
+    pub mtime: Option<DateTime<Utc>>,
+    pub etag: Option<String>, // changes with content, but not necessarily a checksum
+}
@@ -2,14 +2,21 @@ use std::collections::HashMap;
 use std::path::PathBuf;
 
 pub use bytestream::*;
+pub use cache::*;
+pub use gc::*;
 pub use lockfile::*;
+pub use meta::*;
 pub use package::*;
 
 use crate::error::StorageError;
 
 mod bytestream;
+mod cache;
+mod gc;
 mod lockfile;
+mod meta;
 mod package;
 
 pub(crate) type Result<T> = std::result::Result<T, StorageError>;
 pub(crate) type MetaKeyMap = HashMap<PackageMeta, PathBuf>;
+pub(crate) type DigestMap = HashMap<PathBuf, String>; // key->sha256
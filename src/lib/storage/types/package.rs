@@ -69,4 +69,6 @@ impl LocalPackageUnit {
 pub struct RemotePackageUnit {
     pub meta: PackageMeta,
     pub key: PathBuf,
+    #[serde(default)]
+    pub sha256: Option<String>, // in hex, missing in lock files written by older versions
 }
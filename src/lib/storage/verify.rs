@@ -0,0 +1,200 @@
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use std::process::Stdio;
+
+use tempfile::tempdir;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+
+use crate::database::{DBBuilder, PacmanDB};
+use crate::error::{Error, GpgError, Result, StorageError};
+use crate::storage::pool::{LOCK_FILE, RE_PROTECTED};
+use crate::storage::types::LockFile;
+use crate::storage::StorageProvider;
+use crate::utils::map_gpg_code;
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum VerifyIssue {
+    // db entry points to a file that doesn't exist
+    MissingFile {
+        name: String,
+        key: PathBuf,
+    },
+    SizeMismatch {
+        name: String,
+        expected: u64,
+        actual: u64,
+    },
+    MD5Mismatch {
+        name: String,
+    },
+    SHA256Mismatch {
+        name: String,
+    },
+    BadSignature {
+        name: String,
+        error: GpgError,
+    },
+    // db entry whose file name isn't a plain file name, e.g. `../x` or `a/b`
+    InvalidFileName {
+        name: String,
+        file_name: String,
+    },
+    // stored file referenced by neither db nor lock file
+    OrphanedFile(PathBuf),
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub issues: Vec<VerifyIssue>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+async fn read_all(storage: &impl StorageProvider, key: &Path) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    storage
+        .get_file(key)
+        .await?
+        .read_to_end(&mut data)
+        .await
+        .map_err(StorageError::from)?;
+    Ok(data)
+}
+
+// only a single normal component may be joined onto the repo directory
+fn is_plain_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    )
+}
+
+async fn check_signature(sig: &[u8], path: &Path) -> Result<Option<GpgError>> {
+    let mut sig_path = path.as_os_str().to_os_string();
+    sig_path.push(".sig");
+    tokio::fs::write(&sig_path, sig).await?;
+    let status = Command::new("gpg")
+        .args(&["--batch", "--verify"])
+        .arg(&sig_path)
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await?;
+    Ok(status.code().map_or(Some(GpgError::Signal), map_gpg_code))
+}
+
+// Check a published repository for consistency.
+// `db_key` is the key of `{repo}.db` in storage, package files are expected beside it.
+pub async fn verify_repo(storage: &impl StorageProvider, db_key: &Path) -> Result<VerifyReport> {
+    let db = PacmanDB::from_reader(&*read_all(storage, db_key).await?)?;
+    let base = db_key.parent().unwrap_or_else(|| Path::new(""));
+    let workdir = tempdir()?;
+
+    let mut report = VerifyReport::default();
+    let mut referenced = HashSet::new();
+    for entry in db.iter() {
+        let name = entry.desc.name.clone();
+        if !is_plain_file_name(&entry.desc.file_name) {
+            report.issues.push(VerifyIssue::InvalidFileName {
+                name,
+                file_name: entry.desc.file_name.clone(),
+            });
+            continue;
+        }
+        let key = base.join(&entry.desc.file_name);
+        referenced.insert(key.clone());
+
+        let data = match storage.get_file(&key).await {
+            Ok(data) => data,
+            Err(StorageError::FileNotExists(_)) => {
+                report.issues.push(VerifyIssue::MissingFile { name, key });
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        report.checked += 1;
+
+        // download to disk so that large packages aren't kept in memory
+        let path = workdir.path().join(&entry.desc.file_name);
+        data.into_file(&path).await?;
+
+        let checksums = DBBuilder::checksum(std::fs::File::open(&path)?)?;
+        if checksums.size != entry.desc.compressed_size {
+            report.issues.push(VerifyIssue::SizeMismatch {
+                name: name.clone(),
+                expected: entry.desc.compressed_size,
+                actual: checksums.size,
+            });
+        }
+        if checksums.md5 != entry.desc.md5_sum {
+            report
+                .issues
+                .push(VerifyIssue::MD5Mismatch { name: name.clone() });
+        }
+        if checksums.sha256 != entry.desc.sha256_sum {
+            report
+                .issues
+                .push(VerifyIssue::SHA256Mismatch { name: name.clone() });
+        }
+        if let Some(sig) = &entry.desc.pgp_signature {
+            let result = match base64::decode(sig) {
+                Ok(sig) => check_signature(&sig, &path).await?,
+                Err(_) => Some(GpgError::BadSignature),
+            };
+            if let Some(error) = result {
+                report
+                    .issues
+                    .push(VerifyIssue::BadSignature { name, error });
+            }
+        }
+
+        tokio::fs::remove_file(&path).await?;
+    }
+
+    // packages tracked by lock file may be awaiting a db update
+    match read_all(storage, &base.join(LOCK_FILE)).await {
+        Ok(data) => {
+            let lock_file: LockFile = serde_json::from_slice(&data).map_err(StorageError::from)?;
+            referenced.extend(
+                lock_file
+                    .packages
+                    .into_iter()
+                    .map(|unit| base.join(unit.key)),
+            );
+        }
+        Err(Error::StorageError(StorageError::FileNotExists(_))) => (),
+        Err(e) => return Err(e),
+    }
+
+    // stored files beside the db that nothing refers to
+    let mut orphaned = storage
+        .list(base)
+        .await?
+        .into_iter()
+        .map(|meta| meta.path)
+        .filter(|path| path.parent().unwrap_or_else(|| Path::new("")) == base)
+        .filter(|path| !referenced.contains(path))
+        .filter(|path| {
+            // detached signature of a referenced package
+            path.to_str()
+                .and_then(|path| path.strip_suffix(".sig"))
+                .map_or(true, |pkg| !referenced.contains(Path::new(pkg)))
+        })
+        .filter(|path| !RE_PROTECTED.is_match(&path.to_string_lossy()))
+        .collect::<Vec<_>>();
+    orphaned.sort();
+    report
+        .issues
+        .extend(orphaned.into_iter().map(VerifyIssue::OrphanedFile));
+
+    Ok(report)
+}
@@ -0,0 +1,255 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use itertools::Itertools;
+
+use crate::error::{Error, Result, UpdateError};
+use crate::repository::*;
+use crate::resolver::types::PlanAction;
+use crate::storage::types::{LockFile, PackageMeta};
+use crate::types::*;
+
+use super::vcs::{is_vcs_package, vcs_update};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OutdatedPackage {
+    pub meta: PackageMeta,
+    pub latest: Package,
+    // version detected from upstream vcs repository
+    pub vcs_version: Option<Version>,
+}
+
+impl OutdatedPackage {
+    pub fn name(&self) -> &str {
+        self.meta.name.as_str()
+    }
+
+    pub const fn current_version(&self) -> &Version {
+        &self.meta.version
+    }
+
+    pub fn latest_version(&self) -> Version {
+        self.vcs_version
+            .clone()
+            .unwrap_or_else(|| self.latest.version().into_owned())
+    }
+}
+
+// a vcs package whose upstream can't be checked, e.g. unreachable or unknown upstream
+#[derive(Debug)]
+pub struct UncheckedPackage {
+    pub meta: PackageMeta,
+    pub error: Error,
+}
+
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub outdated: Vec<OutdatedPackage>,
+    pub unchecked: Vec<UncheckedPackage>,
+}
+
+// a package depends on another one if any of its (make)depends
+// is named after or provided by the other package
+fn depends_on(pkg: &Package, target: &Package) -> bool {
+    let target_names = target
+        .provides()
+        .iter()
+        .map(|provide| provide.name.clone())
+        .chain([target.name().to_string()])
+        .collect::<HashSet<_>>();
+    pkg.depends()
+        .iter()
+        .chain(pkg.make_depends().iter())
+        .any(|dep| target_names.contains(&dep.name))
+}
+
+// post-order dfs, cycles are broken arbitrarily
+fn visit_deps_first<'a>(
+    pkg: &'a Package,
+    targets: &[&'a Package],
+    visited: &mut HashSet<&'a str>,
+    ordered: &mut Vec<&'a Package>,
+) {
+    if !visited.insert(pkg.name()) {
+        return;
+    }
+    for dep in targets.iter().filter(|target| depends_on(pkg, target)) {
+        visit_deps_first(dep, targets, visited, ordered);
+    }
+    ordered.push(pkg);
+}
+
+// newest published version of each package in the lock file
+pub(crate) fn newest_published(lock_file: &LockFile) -> HashMap<String, PackageMeta> {
+    lock_file
+        .packages
+        .iter()
+        .map(|unit| (unit.meta.name.clone(), unit.meta.clone()))
+        .into_group_map()
+        .into_iter()
+        .map(|(name, metas)| {
+            let newest = metas
+                .into_iter()
+                .max_by(|a, b| a.version.cmp(&b.version))
+                .unwrap();
+            (name, newest)
+        })
+        .collect()
+}
+
+pub struct UpdateChecker {
+    repo: ArcRepo,
+    vcs: bool,
+}
+
+impl Default for UpdateChecker {
+    fn default() -> Self {
+        Self::new_with_custom(vec![])
+    }
+}
+
+impl UpdateChecker {
+    pub fn new(repo: ArcRepo) -> Self {
+        Self { repo, vcs: false }
+    }
+
+    // also check upstream repositories of vcs (-git/-hg/-svn) packages
+    // packages whose upstream can't be checked are reported as unchecked
+    setter_copy!(vcs, bool);
+
+    // custom PKGBUILDs take precedence over aur packages with the same name
+    pub fn new_with_custom(custom_pkgs: Vec<Package>) -> Self {
+        let aur_repo = Arc::new(CachedRepository::new(Arc::new(AurRepo::new())));
+        let custom_repo = Arc::new(CustomRepository::new(custom_pkgs));
+        Self::new(Arc::new(MergedRepository::new(vec![custom_repo, aur_repo])))
+    }
+
+    // query upstream packages by exact name
+    fn upstream(&self, names: impl Iterator<Item = String>) -> Result<HashMap<String, Package>> {
+        let deps = names
+            .map(|name| {
+                Depend::from_str(&*name).map_err(|_| UpdateError::InvalidPackageName(name.clone()))
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(self
+            .repo
+            .find_packages(&*deps)?
+            .into_iter()
+            .filter_map(|(dep, candidates)| {
+                candidates
+                    .into_iter()
+                    .filter(|candidate| candidate.name() == dep.name)
+                    .max_by(|a, b| a.version().cmp(&b.version()))
+                    .map(|pkg| (dep.name, pkg))
+            })
+            .collect())
+    }
+
+    fn outdated(
+        &self,
+        published: &HashMap<String, PackageMeta>,
+        upstream: &HashMap<String, Package>,
+    ) -> CheckReport {
+        let mut report = CheckReport::default();
+        for meta in published.values() {
+            if let Some(latest) = upstream.get(&meta.name) {
+                let vcs_version = if *latest.version() > meta.version {
+                    None
+                } else if self.vcs && is_vcs_package(&meta.name) {
+                    // static pkgver in metadata, ask upstream repository instead
+                    // an unreachable or unknown upstream doesn't fail the whole check
+                    match vcs_update(latest, &meta.version) {
+                        Ok(Some(version)) => Some(version),
+                        Ok(None) => continue,
+                        Err(error) => {
+                            report.unchecked.push(UncheckedPackage {
+                                meta: meta.clone(),
+                                error,
+                            });
+                            continue;
+                        }
+                    }
+                } else {
+                    continue;
+                };
+                report.outdated.push(OutdatedPackage {
+                    meta: meta.clone(),
+                    latest: latest.clone(),
+                    vcs_version,
+                });
+            }
+        }
+        report
+            .outdated
+            .sort_by(|a, b| a.meta.name.cmp(&b.meta.name));
+        report
+            .unchecked
+            .sort_by(|a, b| a.meta.name.cmp(&b.meta.name));
+        report
+    }
+
+    // list published packages which have a newer version upstream,
+    // and vcs packages whose upstream can't be checked
+    pub fn check(&self, lock_file: &LockFile) -> Result<CheckReport> {
+        let published = newest_published(lock_file);
+        let upstream = self.upstream(published.keys().cloned())?;
+        Ok(self.outdated(&published, &upstream))
+    }
+
+    // build outdated packages and all published packages depending on them
+    // NOTE
+    // unchecked vcs packages aren't rebuilt, see `check` for them
+    pub fn rebuild_plan(&self, lock_file: &LockFile) -> Result<Vec<PlanAction>> {
+        let published = newest_published(lock_file);
+        let upstream = self.upstream(published.keys().cloned())?;
+
+        let mut rebuild: HashSet<String> = self
+            .outdated(&published, &upstream)
+            .outdated
+            .into_iter()
+            .map(|outdated| outdated.meta.name)
+            .collect();
+
+        // collect reverse dependencies until a fixpoint is reached
+        loop {
+            let new_deps = upstream
+                .values()
+                .filter(|pkg| !rebuild.contains(pkg.name()))
+                .filter(|pkg| {
+                    rebuild
+                        .iter()
+                        .filter_map(|name| upstream.get(name))
+                        .any(|target| depends_on(pkg, target))
+                })
+                .map(|pkg| pkg.name().to_string())
+                .collect_vec();
+            if new_deps.is_empty() {
+                break;
+            }
+            rebuild.extend(new_deps);
+        }
+
+        // order packages so that dependencies are built first
+        let mut visited = HashSet::new();
+        let mut ordered = vec![];
+        let targets = rebuild
+            .iter()
+            .sorted()
+            .filter_map(|name| upstream.get(name))
+            .collect_vec();
+        for pkg in &targets {
+            visit_deps_first(pkg, &targets, &mut visited, &mut ordered);
+        }
+
+        Ok(ordered
+            .into_iter()
+            .flat_map(|pkg| {
+                [
+                    PlanAction::Build(pkg.clone()),
+                    PlanAction::CopyToDest(pkg.clone()),
+                ]
+            })
+            .collect())
+    }
+}
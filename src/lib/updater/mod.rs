@@ -0,0 +1,10 @@
+pub use checker::*;
+pub use soname::*;
+pub use vcs::*;
+
+mod checker;
+mod soname;
+mod vcs;
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,123 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::Path;
+use std::str::FromStr;
+
+use alpm::Alpm;
+use itertools::Itertools;
+use tar::Archive as TarArchive;
+
+use crate::alpm::GLOBAL_ALPM;
+use crate::database::{ArchiveReader, DBBuilder, Soname};
+use crate::error::Result;
+use crate::storage::types::{LockFile, PackageMeta};
+use crate::storage::{PackagePool, StorageProvider};
+use crate::types::*;
+
+use super::checker::newest_published;
+
+// sonames provided by sync databases, e.g. `libfoo.so=2-64`
+#[derive(Debug, Clone, Default)]
+pub struct SonameIndex {
+    provides: HashMap<String, HashSet<Soname>>,
+}
+
+impl SonameIndex {
+    pub fn new(provides: impl IntoIterator<Item = Depend>) -> Self {
+        let mut index = Self::default();
+        for soname in provides
+            .into_iter()
+            .filter_map(|dep| Soname::from_depend(&dep))
+        {
+            index
+                .provides
+                .entry(soname.name.clone())
+                .or_default()
+                .insert(soname);
+        }
+        index
+    }
+
+    pub fn from_alpm(alpm: &Alpm) -> Self {
+        let mut provides = vec![];
+        for db in alpm.syncdbs().iter() {
+            for pkg in db.pkgs().iter() {
+                provides.extend(pkg.provides().iter().map(Depend::from));
+            }
+        }
+        Self::new(provides)
+    }
+
+    // a soname is broken if the library is still provided, but with another version
+    // libraries unknown to the index (e.g. not declared in `provides`) are never reported
+    pub fn is_broken(&self, soname: &Soname) -> bool {
+        self.provides.get(&soname.name).map_or(false, |provided| {
+            provided.iter().any(|p| p.bits == soname.bits) && !provided.contains(soname)
+        })
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SonameRebuild {
+    pub meta: PackageMeta,
+    pub broken: Vec<Soname>,
+}
+
+pub struct SonameChecker {
+    index: SonameIndex,
+}
+
+impl Default for SonameChecker {
+    fn default() -> Self {
+        Self::new(SonameIndex::from_alpm(&*GLOBAL_ALPM.lock().unwrap()))
+    }
+}
+
+impl SonameChecker {
+    pub fn new(index: SonameIndex) -> Self {
+        Self { index }
+    }
+
+    // list sonames the package links against which are no longer provided
+    pub fn check_file(&self, path: &Path) -> Result<Vec<Soname>> {
+        let archive = ArchiveReader::stream(File::open(path)?)?;
+        let (links, info) = DBBuilder::collect_links(TarArchive::new(archive))?;
+
+        // makepkg also records linked libraries in depends, e.g. `libfoo.so=2-64`
+        let declared = info.into_iter().flat_map(|info| {
+            info.depend
+                .into_iter()
+                .filter_map(|dep| Depend::from_str(&*dep).ok())
+                .filter_map(|dep| Soname::from_depend(&dep))
+        });
+
+        Ok(links
+            .needed
+            .into_iter()
+            .chain(declared)
+            .filter(|soname| self.index.is_broken(soname))
+            .unique()
+            .sorted_by(|a, b| a.to_string().cmp(&b.to_string()))
+            .collect())
+    }
+
+    // check the newest version of each published package
+    // packages are fetched and checked one by one, a bounded cache may evict earlier ones
+    pub async fn check_pool<T: StorageProvider>(
+        &self,
+        pool: &mut PackagePool<T>,
+        lock_file: &LockFile,
+    ) -> Result<Vec<SonameRebuild>> {
+        let mut rebuilds = vec![];
+        for meta in newest_published(lock_file).into_values() {
+            if let Some(path) = pool.get(&meta).await? {
+                let broken = self.check_file(&path)?;
+                if !broken.is_empty() {
+                    rebuilds.push(SonameRebuild { meta, broken });
+                }
+            }
+        }
+        rebuilds.sort_by(|a, b| a.meta.name.cmp(&b.meta.name));
+        Ok(rebuilds)
+    }
+}
@@ -0,0 +1,280 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use itertools::Itertools;
+use tempfile::tempdir;
+
+use crate::database::Soname;
+use crate::error::UpdateError;
+use crate::tests::*;
+use crate::updater::*;
+
+fn lock_file_of(pkgs: &[(&str, &str)]) -> LockFile {
+    let mut lock_file = LockFile::new();
+    lock_file.packages = pkgs
+        .iter()
+        .map(|(name, ver)| {
+            let meta = PackageMeta::new(name, &Version(ver.to_string()), 0);
+            RemotePackageUnit {
+                key: PathBuf::from(meta.filename()),
+                meta,
+                sha256: None,
+            }
+        })
+        .collect();
+    lock_file
+}
+
+fn setup_checker() -> UpdateChecker {
+    UpdateChecker::new(Arc::new(CustomRepository::new(vec![
+        pkg!("a", "1.1.0"),
+        pkg!("b", "1.0.0", deps!("a")),
+        pkg!("c", "1.0.0", deps!("b")),
+        pkg!("d", "1.0.0"),
+        pkg!("e", "2.0.0", vec![], deps!("c"), vec![], vec![]),
+        pkg!("f", "1.0.0", deps!("a-virtual")),
+        pkg!(
+            "a-impl",
+            "1.0.0",
+            vec![],
+            vec![],
+            vec![],
+            deps!("a-virtual")
+        ),
+    ])))
+}
+
+#[test]
+fn must_check_outdated() {
+    let lock_file = lock_file_of(&[
+        ("a", "1.0.0"),
+        ("a", "0.9.0"),
+        ("b", "1.0.0"),
+        ("d", "1.0.0"),
+        ("e", "2.0.0"),
+        ("missing", "1.0.0"),
+    ]);
+    let outdated = setup_checker()
+        .check(&lock_file)
+        .expect("unable to check updates")
+        .outdated;
+    assert_eq!(outdated.len(), 1, "outdated count mismatch");
+    let a = outdated.first().unwrap();
+    assert_eq!(a.name(), "a", "outdated package mismatch");
+    assert_eq!(a.current_version(), &Version(String::from("1.0.0")));
+    assert_eq!(a.latest_version(), Version(String::from("1.1.0")));
+
+    let lock_file = lock_file_of(&[("a", "1.1.0"), ("b", "1.0.0")]);
+    assert!(
+        setup_checker()
+            .check(&lock_file)
+            .expect("unable to check updates")
+            .outdated
+            .is_empty(),
+        "up-to-date packages reported"
+    );
+}
+
+#[test]
+fn must_report_unchecked_vcs_packages() {
+    // no vcs source to query
+    let checker = UpdateChecker::new(Arc::new(CustomRepository::new(vec![
+        pkg!("a", "1.1.0"),
+        pkg!("foo-git", "r1.abcdef0-1"),
+    ])))
+    .vcs(true);
+    let lock_file = lock_file_of(&[("a", "1.0.0"), ("foo-git", "r1.abcdef0-1")]);
+    let report = checker.check(&lock_file).expect("unable to check updates");
+    assert_eq!(report.outdated.len(), 1, "outdated count mismatch");
+    assert_eq!(report.outdated.first().unwrap().name(), "a");
+    assert_eq!(report.unchecked.len(), 1, "unchecked count mismatch");
+    let unchecked = report.unchecked.first().unwrap();
+    assert_eq!(unchecked.meta.name, "foo-git");
+    assert!(matches!(
+        unchecked.error,
+        Error::UpdateError(UpdateError::MissingSource)
+    ));
+}
+
+#[test]
+fn must_plan_rebuild() {
+    let lock_file = lock_file_of(&[
+        ("a", "1.0.0"),
+        ("b", "1.0.0"),
+        ("c", "1.0.0"),
+        ("d", "1.0.0"),
+        ("e", "2.0.0"),
+    ]);
+    let plan = setup_checker()
+        .rebuild_plan(&lock_file)
+        .expect("unable to plan rebuild");
+    let built = plan
+        .iter()
+        .filter_map(|action| match action {
+            PlanAction::Build(pkg) => Some(pkg),
+            _ => None,
+        })
+        .collect_vec();
+    assert_eq!(built.len(), 4, "rebuild count mismatch");
+    for asrt in [asrt!("a" < "b" < "c" < "e"), asrt!(!"d")] {
+        asrt.assert(&built);
+    }
+
+    let lock_file = lock_file_of(&[("a-impl", "0.9.0"), ("f", "1.0.0"), ("d", "1.0.0")]);
+    let plan = setup_checker()
+        .rebuild_plan(&lock_file)
+        .expect("unable to plan rebuild");
+    let built = plan
+        .iter()
+        .filter_map(|action| match action {
+            PlanAction::Build(pkg) => Some(pkg),
+            _ => None,
+        })
+        .collect_vec();
+    for asrt in [asrt!("a-impl" < "f"), asrt!(!"d")] {
+        asrt.assert(&built);
+    }
+}
+
+#[test]
+fn must_parse_vcs_source() {
+    assert_eq!(
+        VcsSource::parse("foo::git+https://example.com/foo.git#branch=dev"),
+        Some(VcsSource {
+            kind: VcsKind::Git,
+            url: String::from("https://example.com/foo.git"),
+            reference: VcsRef::Branch(String::from("dev")),
+        })
+    );
+    assert_eq!(
+        VcsSource::parse("git://example.com/foo.git?signed#tag=v1.0"),
+        Some(VcsSource {
+            kind: VcsKind::Git,
+            url: String::from("git://example.com/foo.git"),
+            reference: VcsRef::Tag(String::from("v1.0")),
+        })
+    );
+    assert_eq!(
+        VcsSource::parse("svn+https://example.com/svn/foo"),
+        Some(VcsSource {
+            kind: VcsKind::Svn,
+            url: String::from("https://example.com/svn/foo"),
+            reference: VcsRef::Default,
+        })
+    );
+    assert_eq!(VcsSource::parse("https://example.com/foo.tar.gz"), None);
+    assert_eq!(VcsSource::parse("foo.patch"), None);
+
+    assert!(is_vcs_package("foo-git"));
+    assert!(!is_vcs_package("foo"));
+    assert!(!is_vcs_package("foo-bzr"));
+
+    let version = Version(String::from("1:r12.abcdef-2"));
+    assert_eq!(
+        split_version(&version),
+        (Some("1"), "r12.abcdef", Some("2"))
+    );
+    let version = Version(String::from("1.0"));
+    assert_eq!(split_version(&version), (None, "1.0", None));
+}
+
+fn git(repo: &Path, args: &[&str]) -> String {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(&[
+            "-c",
+            "user.name=archer",
+            "-c",
+            "user.email=archer@localhost",
+        ])
+        .args(args)
+        .output()
+        .expect("unable to run git");
+    assert!(output.status.success(), "git failed");
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+}
+
+#[test]
+fn must_detect_git_pkgver() {
+    let repo = tempdir().expect("unable to create temp dir");
+    git(repo.path(), &["init", "--quiet"]);
+    git(
+        repo.path(),
+        &["commit", "--quiet", "--allow-empty", "-m", "1"],
+    );
+    git(
+        repo.path(),
+        &["commit", "--quiet", "--allow-empty", "-m", "2"],
+    );
+
+    let source = VcsSource::parse(&*format!("git+file://{}", repo.path().display())).unwrap();
+    let hash = git(repo.path(), &["rev-parse", "--short", "HEAD"]);
+    assert_eq!(
+        source
+            .pkgver("r1.0000000")
+            .expect("unable to detect pkgver"),
+        format!("r2.{}", hash),
+        "rev count pkgver mismatch"
+    );
+
+    git(repo.path(), &["tag", "v1.0"]);
+    git(
+        repo.path(),
+        &["commit", "--quiet", "--allow-empty", "-m", "3"],
+    );
+    let hash = git(repo.path(), &["rev-parse", "--short", "HEAD"]);
+    assert_eq!(
+        source.pkgver("1.0").expect("unable to detect pkgver"),
+        format!("1.0.r1.g{}", hash),
+        "describe pkgver mismatch"
+    );
+    assert!(
+        alpm::vercmp(
+            source.pkgver("0.9.r0.g0000000").unwrap().as_str(),
+            "0.9.r0.g0000000"
+        )
+        .is_gt(),
+        "newer pkgver not detected"
+    );
+}
+
+#[test]
+fn must_detect_soname_bump() {
+    let path = Path::new("tests/pkgs/acl-2.3.1-1-x86_64.pkg.tar.zst");
+    let libc = Soname::parse("libc.so.6", true).unwrap();
+    assert_eq!(libc.to_string(), "libc.so=6-64");
+    assert_eq!(
+        Soname::from_depend(&dep!("libc.so=6-64")),
+        Some(libc.clone())
+    );
+    assert_eq!(Soname::from_depend(&dep!("libc.so")), None);
+
+    // libacl.so is shipped by acl itself
+    let checker = SonameChecker::new(SonameIndex::new(deps!("libc.so=6-64", "libacl.so=2-64")));
+    assert!(
+        checker
+            .check_file(path)
+            .expect("unable to check package")
+            .is_empty(),
+        "unexpected broken soname"
+    );
+
+    let checker = SonameChecker::new(SonameIndex::new(deps!("libc.so=7-64")));
+    assert_eq!(
+        checker.check_file(path).expect("unable to check package"),
+        vec![libc],
+        "broken soname mismatch"
+    );
+
+    // 32-bit providers don't affect 64-bit packages
+    let checker = SonameChecker::new(SonameIndex::new(deps!("libc.so=7-32")));
+    assert!(
+        checker
+            .check_file(path)
+            .expect("unable to check package")
+            .is_empty(),
+        "unexpected broken soname"
+    );
+}
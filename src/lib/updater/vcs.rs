@@ -0,0 +1,256 @@
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use tempfile::tempdir;
+
+use crate::error::{CommandError, Result, UpdateError};
+use crate::types::*;
+
+const AUR_SRCINFO_URL: &str = "https://aur.archlinux.org/cgit/aur.git/plain/.SRCINFO";
+const VCS_SUFFIXES: [&str; 3] = ["-git", "-hg", "-svn"];
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum VcsKind {
+    Git,
+    Hg,
+    Svn,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum VcsRef {
+    Default,
+    Branch(String),
+    Tag(String),
+    Commit(String),
+    Revision(String),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct VcsSource {
+    pub kind: VcsKind,
+    pub url: String,
+    pub reference: VcsRef,
+}
+
+pub fn is_vcs_package(name: &str) -> bool {
+    VCS_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+}
+
+impl VcsSource {
+    // parse a makepkg source entry, e.g. `foo::git+https://example.com/foo.git#branch=dev`
+    // returns None if it's not a vcs source
+    pub fn parse(source: &str) -> Option<Self> {
+        let source = source.split_once("::").map_or(source, |(_, url)| url);
+        let (url, fragment) = source
+            .split_once('#')
+            .map_or((source, None), |(url, fragment)| (url, Some(fragment)));
+        let url = url.split_once('?').map_or(url, |(url, _)| url); // strip `?signed`
+
+        let (kind, url) = if let Some(url) = url.strip_prefix("git+") {
+            (VcsKind::Git, url)
+        } else if let Some(url) = url.strip_prefix("hg+") {
+            (VcsKind::Hg, url)
+        } else if let Some(url) = url.strip_prefix("svn+") {
+            (VcsKind::Svn, url)
+        } else if url.starts_with("git://") {
+            (VcsKind::Git, url)
+        } else if url.starts_with("svn://") {
+            (VcsKind::Svn, url)
+        } else {
+            return None;
+        };
+
+        let reference = match fragment.and_then(|fragment| fragment.split_once('=')) {
+            Some(("branch", v)) => VcsRef::Branch(v.to_string()),
+            Some(("tag", v)) => VcsRef::Tag(v.to_string()),
+            Some(("commit", v)) => VcsRef::Commit(v.to_string()),
+            Some(("revision", v)) => VcsRef::Revision(v.to_string()),
+            _ => VcsRef::Default,
+        };
+
+        Some(Self {
+            kind,
+            url: url.to_string(),
+            reference,
+        })
+    }
+
+    // emulate the conventional `pkgver()` of vcs packages
+    // `current` is the pkgver of the published package, used to pick the version scheme
+    pub fn pkgver(&self, current: &str) -> Result<String> {
+        let workdir = tempdir()?;
+        let repo = workdir.path().join("repo");
+        match self.kind {
+            VcsKind::Git => self.git_pkgver(&repo, current),
+            VcsKind::Hg => self.hg_pkgver(&repo),
+            VcsKind::Svn => self.svn_pkgver(),
+        }
+    }
+
+    fn git_pkgver(&self, repo: &Path, current: &str) -> Result<String> {
+        run(
+            Command::new("git")
+                .args(&["clone", "--quiet", "--bare", "--filter=blob:none"])
+                .arg(&self.url)
+                .arg(repo),
+            CommandError::Git,
+        )?;
+
+        let rev = match &self.reference {
+            VcsRef::Branch(rev) | VcsRef::Tag(rev) | VcsRef::Commit(rev) => rev.as_str(),
+            VcsRef::Default | VcsRef::Revision(_) => "HEAD",
+        };
+
+        // r<count>.<short hash> scheme, e.g. r1234.abcdef0
+        let rev_count_scheme = current
+            .strip_prefix('r')
+            .map_or(false, |s| s.starts_with(|c: char| c.is_ascii_digit()));
+        if !rev_count_scheme {
+            // <tag>.r<count>.g<short hash> scheme, e.g. 1.2.3.r4.gabcdef0
+            let describe = run(
+                Command::new("git")
+                    .arg("-C")
+                    .arg(repo)
+                    .args(&["describe", "--long", "--tags", rev]),
+                CommandError::Git,
+            );
+            if let Ok(describe) = describe {
+                let mut parts = describe.rsplitn(3, '-');
+                if let (Some(hash), Some(count), Some(tag)) =
+                    (parts.next(), parts.next(), parts.next())
+                {
+                    return Ok(format!(
+                        "{}.r{}.{}",
+                        tag.trim_start_matches('v').replace('-', "."),
+                        count,
+                        hash
+                    ));
+                }
+            }
+        }
+
+        let count = run(
+            Command::new("git")
+                .arg("-C")
+                .arg(repo)
+                .args(&["rev-list", "--count", rev]),
+            CommandError::Git,
+        )?;
+        let hash = run(
+            Command::new("git")
+                .arg("-C")
+                .arg(repo)
+                .args(&["rev-parse", "--short", rev]),
+            CommandError::Git,
+        )?;
+        Ok(format!("r{}.{}", count, hash))
+    }
+
+    fn hg_pkgver(&self, repo: &Path) -> Result<String> {
+        run(
+            Command::new("hg")
+                .args(&["clone", "--quiet", "--noupdate"])
+                .arg(&self.url)
+                .arg(repo),
+            CommandError::Hg,
+        )?;
+        let rev = match &self.reference {
+            VcsRef::Branch(rev) | VcsRef::Tag(rev) | VcsRef::Revision(rev) => rev.as_str(),
+            VcsRef::Default | VcsRef::Commit(_) => "tip",
+        };
+        let output = run(
+            Command::new("hg").arg("--repository").arg(repo).args(&[
+                "log",
+                "--rev",
+                rev,
+                "--template",
+                "{rev}.{node|short}",
+            ]),
+            CommandError::Hg,
+        )?;
+        Ok(format!("r{}", output))
+    }
+
+    fn svn_pkgver(&self) -> Result<String> {
+        let mut cmd = Command::new("svn");
+        cmd.args(&["info", "--show-item", "last-changed-revision"]);
+        if let VcsRef::Revision(rev) = &self.reference {
+            cmd.arg("--revision").arg(rev);
+        }
+        let output = run(cmd.arg(&self.url), CommandError::Svn)?;
+        Ok(format!("r{}", output))
+    }
+}
+
+fn run(cmd: &mut Command, err: CommandError) -> Result<String> {
+    let output = cmd.stdin(Stdio::null()).stderr(Stdio::null()).output()?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    } else {
+        Err(UpdateError::CommandError(err).into())
+    }
+}
+
+// split `epoch:pkgver-pkgrel` into its parts
+pub fn split_version(version: &Version) -> (Option<&str>, &str, Option<&str>) {
+    let (epoch, rest) = version
+        .as_ref()
+        .split_once(':')
+        .map_or((None, version.as_ref()), |(epoch, rest)| {
+            (Some(epoch), rest)
+        });
+    let (pkgver, pkgrel) = rest
+        .rsplit_once('-')
+        .map_or((rest, None), |(pkgver, pkgrel)| (pkgver, Some(pkgrel)));
+    (epoch, pkgver, pkgrel)
+}
+
+// AUR rpc doesn't carry sources, so they are read from .SRCINFO
+fn aur_sources(pkg_base: &str) -> Result<Vec<String>> {
+    let srcinfo = reqwest::blocking::get(format!("{}?h={}", AUR_SRCINFO_URL, pkg_base))
+        .and_then(reqwest::blocking::Response::error_for_status)
+        .and_then(reqwest::blocking::Response::text)
+        .map_err(UpdateError::from)?;
+    Ok(srcinfo
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("source"))
+        .filter_map(|line| line.split_once('=')) // also matches source_<arch>
+        .map(|(_, source)| source.trim().to_string())
+        .collect())
+}
+
+fn sources(pkg: &Package) -> Result<Vec<String>> {
+    Ok(match pkg {
+        Package::CustomPackage(pkg) => pkg.data.source.clone().unwrap_or_default(),
+        Package::AurPackage(pkg) => aur_sources(&pkg.package_base)?,
+        Package::PacmanPackage(_) => vec![],
+    })
+}
+
+pub fn vcs_sources(pkg: &Package) -> Result<Vec<VcsSource>> {
+    Ok(sources(pkg)?
+        .iter()
+        .filter_map(|source| VcsSource::parse(source))
+        .collect())
+}
+
+// detect the upstream version of a vcs package, None if it's up to date
+pub fn vcs_update(pkg: &Package, current: &Version) -> Result<Option<Version>> {
+    let sources = sources(pkg)?;
+    let first = sources.first().ok_or(UpdateError::MissingSource)?;
+    // e.g. bzr sources, or a vcs package built from a tarball
+    let source = sources
+        .iter()
+        .find_map(|source| VcsSource::parse(source))
+        .ok_or_else(|| UpdateError::UnsupportedSource(first.clone()))?;
+    let (epoch, current_pkgver, _) = split_version(current);
+
+    let pkgver = source.pkgver(current_pkgver)?;
+    let newer = alpm::vercmp(pkgver.as_str(), current_pkgver).is_gt();
+    Ok(newer.then(|| {
+        Version(epoch.map_or_else(
+            || format!("{}-1", pkgver),
+            |epoch| format!("{}:{}-1", epoch, pkgver),
+        ))
+    }))
+}